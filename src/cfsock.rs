@@ -1,7 +1,25 @@
 use libc::*;
 use socket2::{Domain, Socket, Type};
+use std::env;
+use std::io;
 use std::net::SocketAddr;
-use std::os::unix::io::AsRawFd;
+use std::os::unix::io::{AsRawFd, FromRawFd, RawFd};
+use std::process;
+
+/// The first file descriptor passed with systemd socket activation. See sd_listen_fds(3).
+const SD_LISTEN_FDS_START: RawFd = 3;
+
+/// A socket passed by the service manager with socket activation.
+pub struct InheritedSocket {
+    /// The name from `FileDescriptorName=` of the socket unit, which is the name of the unit if
+    /// it's not set.
+    pub name: Option<String>,
+
+    /// The address that the socket is bound to, if it's an IP socket.
+    pub addr: Option<SocketAddr>,
+
+    socket: Socket,
+}
 
 #[cfg(target_os = "linux")]
 fn set_freebind(fd: c_int) -> Result<(), std::io::Error> {
@@ -29,19 +47,154 @@ fn set_freebind(_fd: c_int) -> Result<(), std::io::Error> {
     Ok(()) // no op for mac build
 }
 
-pub fn tcp_listener(addr: &SocketAddr) -> Result<std::net::TcpListener, std::io::Error> {
+/// Let several sockets bind to the same address and port, so that the kernel spreads the
+/// incoming connections across them.
+fn set_reuseport(fd: c_int) -> Result<(), std::io::Error> {
+    match unsafe {
+        setsockopt(
+            fd,
+            SOL_SOCKET,
+            SO_REUSEPORT,
+            &1u32 as *const u32 as *const c_void,
+            std::mem::size_of::<u32>() as u32,
+        )
+    } {
+        -1 => Err(std::io::Error::last_os_error()),
+        _ => Ok(()),
+    }
+}
+
+/// Close the file descriptor when the process executes another program.
+fn set_cloexec(fd: c_int) -> Result<(), std::io::Error> {
+    match unsafe { fcntl(fd, F_SETFD, FD_CLOEXEC) } {
+        -1 => Err(std::io::Error::last_os_error()),
+        _ => Ok(()),
+    }
+}
+
+/// Create a listening TCP socket. If `reuse_port` is true, other sockets with the same option can
+/// listen on the same address.
+pub fn tcp_listener(
+    addr: &SocketAddr,
+    reuse_port: bool,
+) -> Result<std::net::TcpListener, std::io::Error> {
     let domain = match addr {
         SocketAddr::V4(..) => Domain::IPV4,
         SocketAddr::V6(..) => Domain::IPV6,
     };
     let socket = Socket::new(domain, Type::STREAM, None)?;
     socket.set_reuse_address(true)?;
+    if reuse_port {
+        set_reuseport(socket.as_raw_fd())?;
+    }
     set_freebind(socket.as_raw_fd())?;
     socket.bind(&(*addr).into())?;
     socket.listen(128)?;
     Ok(socket.into())
 }
 
+/// Take the sockets passed by systemd socket activation in `LISTEN_FDS` and `LISTEN_FDNAMES`. The
+/// variables are unset, so that they are not passed on to child processes. Return an empty list
+/// if the process is not socket-activated.
+///
+/// This takes the ownership of the file descriptors, so it must be called only once.
+///
+/// # Errors
+///
+/// There will be an error if `LISTEN_FDS` is malformed, or a file descriptor is not a socket.
+///
+pub fn inherited_sockets() -> Result<Vec<InheritedSocket>, io::Error> {
+    let invalid = |message: String| io::Error::new(io::ErrorKind::InvalidInput, message);
+
+    let pid = env::var("LISTEN_PID");
+    let count = env::var("LISTEN_FDS");
+    let names = env::var("LISTEN_FDNAMES");
+    for name in &["LISTEN_PID", "LISTEN_FDS", "LISTEN_FDNAMES"] {
+        env::remove_var(name);
+    }
+
+    let (pid, count) = match (pid, count) {
+        (Ok(pid), Ok(count)) => (pid, count),
+        _ => return Ok(Vec::new()),
+    };
+    // The variables are meant for another process, which has passed them on.
+    if pid.parse::<u32>().ok() != Some(process::id()) {
+        return Ok(Vec::new());
+    }
+    let count: RawFd = match count.parse() {
+        Ok(count) if count >= 0 => count,
+        _ => {
+            return Err(invalid(format!(
+                "LISTEN_FDS is not a valid count: {}",
+                count
+            )))
+        }
+    };
+    let names: Vec<&str> = match &names {
+        Ok(names) => names.split(':').collect(),
+        Err(_) => Vec::new(),
+    };
+
+    let mut sockets = Vec::new();
+    for index in 0..count {
+        let fd = SD_LISTEN_FDS_START + index;
+        // It's safe because the service manager hands the file descriptor over to us, and nothing
+        // else in the process uses it.
+        let socket = unsafe { Socket::from_raw_fd(fd) };
+        set_cloexec(fd)?;
+        let addr = socket.local_addr().map_err(|error| {
+            invalid(format!(
+                "the inherited file descriptor {} is not a socket: {}",
+                fd, error
+            ))
+        })?;
+        sockets.push(InheritedSocket {
+            name: names.get(index as usize).map(|name| String::from(*name)),
+            addr: addr.as_socket(),
+            socket,
+        });
+    }
+    Ok(sockets)
+}
+
+/// Remove the inherited socket for a configured address from `sockets` and return it. If `name`
+/// is given, the socket with that name is taken. Otherwise, the socket bound to `addr` is taken.
+///
+/// Return `None` if there is no such socket, in which case the caller binds its own.
+///
+/// # Errors
+///
+/// There will be an error if the socket is not of type `kind`.
+///
+pub fn take_inherited_socket(
+    sockets: &mut Vec<InheritedSocket>,
+    kind: Type,
+    addr: &SocketAddr,
+    name: Option<&str>,
+) -> Result<Option<Socket>, io::Error> {
+    let position = sockets.iter().position(|socket| match name {
+        Some(name) => socket.name.as_deref() == Some(name),
+        None => socket.addr.as_ref() == Some(addr),
+    });
+    let inherited = match position {
+        Some(position) => sockets.remove(position),
+        None => return Ok(None),
+    };
+
+    if inherited.socket.r#type()? != kind {
+        let kind = if kind == Type::STREAM { "TCP" } else { "UDP" };
+        return Err(io::Error::new(
+            io::ErrorKind::InvalidInput,
+            format!(
+                "the inherited socket for {} is not a {} socket",
+                name.map_or_else(|| addr.to_string(), String::from),
+                kind
+            ),
+        ));
+    }
+    Ok(Some(inherited.socket))
+}
+
 pub fn udp_listen(addr: &SocketAddr) -> Result<std::net::UdpSocket, std::io::Error> {
     let domain = match addr {
         SocketAddr::V4(..) => Domain::IPV4,
@@ -53,3 +206,38 @@ pub fn udp_listen(addr: &SocketAddr) -> Result<std::net::UdpSocket, std::io::Err
     socket.bind(&(*addr).into())?;
     Ok(socket.into())
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn inherited(name: &str, socket: Socket) -> InheritedSocket {
+        InheritedSocket {
+            name: Some(String::from(name)),
+            addr: socket.local_addr().unwrap().as_socket(),
+            socket,
+        }
+    }
+
+    #[test]
+    fn test_take_inherited_socket() {
+        let any: SocketAddr = "127.0.0.1:0".parse().unwrap();
+        let udp = Socket::from(udp_listen(&any).unwrap());
+        let udp_addr = udp.local_addr().unwrap().as_socket().unwrap();
+        let tcp = Socket::from(tcp_listener(&any, false).unwrap());
+        let tcp_addr = tcp.local_addr().unwrap().as_socket().unwrap();
+
+        let mut sockets = vec![inherited("ntp", udp), inherited("ke", tcp)];
+        assert!(take_inherited_socket(&mut sockets, Type::DGRAM, &any, None)
+            .unwrap()
+            .is_none());
+        assert!(take_inherited_socket(&mut sockets, Type::DGRAM, &tcp_addr, Some("ke")).is_err());
+
+        let socket = take_inherited_socket(&mut sockets, Type::DGRAM, &udp_addr, None).unwrap();
+        assert_eq!(
+            socket.unwrap().local_addr().unwrap().as_socket(),
+            Some(udp_addr)
+        );
+        assert!(sockets.is_empty());
+    }
+}
@@ -0,0 +1,176 @@
+// This file is part of cfnts.
+// Copyright (c) 2019, Cloudflare. All rights reserved.
+// See LICENSE for licensing information.
+
+//! IP network prefixes in CIDR notation.
+
+use std::fmt;
+use std::net::{IpAddr, Ipv4Addr, Ipv6Addr};
+use std::str::FromStr;
+
+/// Return the IPv4 address if `addr` is an IPv4-mapped IPv6 address, or `addr` itself if not.
+///
+/// Dual-stack sockets report IPv4 clients as IPv4-mapped IPv6 addresses, but we want to match
+/// them against IPv4 prefixes.
+pub fn canonical_ip(addr: IpAddr) -> IpAddr {
+    match addr {
+        IpAddr::V6(v6) => {
+            let segments = v6.segments();
+            if segments[..5] == [0, 0, 0, 0, 0] && segments[5] == 0xffff {
+                let octets = v6.octets();
+                IpAddr::V4(Ipv4Addr::new(
+                    octets[12], octets[13], octets[14], octets[15],
+                ))
+            } else {
+                addr
+            }
+        }
+        IpAddr::V4(_) => addr,
+    }
+}
+
+/// An IPv4 or IPv6 network prefix, for example `192.0.2.0/24` or `2001:db8::/32`.
+#[derive(Clone, Copy, Debug, Eq, Hash, PartialEq)]
+pub struct Cidr {
+    /// The network address. All the bits after the prefix length are zero.
+    addr: IpAddr,
+    /// The number of leading bits of the network address.
+    prefix_len: u8,
+}
+
+impl Cidr {
+    /// Create a prefix from an address and a prefix length. The host bits of the address are
+    /// cleared.
+    ///
+    /// Return `None` if the prefix length is too long for the address family.
+    pub fn new(addr: IpAddr, prefix_len: u8) -> Option<Cidr> {
+        let addr = canonical_ip(addr);
+        let max_len = Cidr::max_prefix_len(&addr);
+        if prefix_len > max_len {
+            return None;
+        }
+        Some(Cidr {
+            addr: Cidr::mask(addr, prefix_len),
+            prefix_len,
+        })
+    }
+
+    /// Return the number of leading bits of the network address.
+    pub fn prefix_len(&self) -> u8 {
+        self.prefix_len
+    }
+
+    /// Return true if the address is in this network.
+    pub fn contains(&self, addr: &IpAddr) -> bool {
+        let addr = canonical_ip(*addr);
+        match (self.addr, addr) {
+            (IpAddr::V4(_), IpAddr::V4(_)) | (IpAddr::V6(_), IpAddr::V6(_)) => {
+                Cidr::mask(addr, self.prefix_len) == self.addr
+            }
+            _ => false,
+        }
+    }
+
+    /// Return the prefix of length `prefix_len` that contains `addr`.
+    ///
+    /// The prefix length is capped to the length of the address family.
+    pub fn containing(addr: &IpAddr, prefix_len: u8) -> Cidr {
+        let addr = canonical_ip(*addr);
+        let prefix_len = prefix_len.min(Cidr::max_prefix_len(&addr));
+        Cidr {
+            addr: Cidr::mask(addr, prefix_len),
+            prefix_len,
+        }
+    }
+
+    fn max_prefix_len(addr: &IpAddr) -> u8 {
+        match addr {
+            IpAddr::V4(_) => 32,
+            IpAddr::V6(_) => 128,
+        }
+    }
+
+    fn mask(addr: IpAddr, prefix_len: u8) -> IpAddr {
+        match addr {
+            IpAddr::V4(v4) => {
+                let bits = u32::from(v4);
+                let mask = u32::MAX
+                    .checked_shl(32 - u32::from(prefix_len))
+                    .unwrap_or(0);
+                IpAddr::V4(Ipv4Addr::from(bits & mask))
+            }
+            IpAddr::V6(v6) => {
+                let bits = u128::from(v6);
+                let mask = u128::MAX
+                    .checked_shl(128 - u32::from(prefix_len))
+                    .unwrap_or(0);
+                IpAddr::V6(Ipv6Addr::from(bits & mask))
+            }
+        }
+    }
+}
+
+impl FromStr for Cidr {
+    type Err = String;
+
+    /// Parse a prefix like `192.0.2.0/24`. A plain address is treated as a host prefix.
+    fn from_str(s: &str) -> Result<Cidr, String> {
+        let (addr, prefix_len) = match s.find('/') {
+            Some(index) => (&s[..index], Some(&s[index + 1..])),
+            None => (s, None),
+        };
+
+        let addr = IpAddr::from_str(addr)
+            .map_err(|_| format!("{} is not a valid IP address prefix", s))?;
+        let prefix_len = match prefix_len {
+            Some(prefix_len) => u8::from_str(prefix_len)
+                .map_err(|_| format!("{} has an invalid prefix length", s))?,
+            None => Cidr::max_prefix_len(&canonical_ip(addr)),
+        };
+
+        Cidr::new(addr, prefix_len).ok_or_else(|| format!("{} has a too long prefix length", s))
+    }
+}
+
+impl fmt::Display for Cidr {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "{}/{}", self.addr, self.prefix_len)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_cidr() {
+        let v4: Cidr = "192.0.2.77/24".parse().unwrap();
+        assert_eq!(v4.to_string(), "192.0.2.0/24");
+        assert!(v4.contains(&"192.0.2.1".parse().unwrap()));
+        assert!(v4.contains(&"::ffff:192.0.2.1".parse().unwrap()));
+        assert!(!v4.contains(&"192.0.3.1".parse().unwrap()));
+        assert!(!v4.contains(&"2001:db8::1".parse().unwrap()));
+
+        let v6: Cidr = "2001:db8::/32".parse().unwrap();
+        assert!(v6.contains(&"2001:db8:1::1".parse().unwrap()));
+        assert!(!v6.contains(&"2001:db9::1".parse().unwrap()));
+
+        let any: Cidr = "0.0.0.0/0".parse().unwrap();
+        assert!(any.contains(&"203.0.113.1".parse().unwrap()));
+
+        let host: Cidr = "203.0.113.1".parse().unwrap();
+        assert_eq!(host.prefix_len(), 32);
+        assert_eq!(
+            Cidr::containing(&"203.0.113.200".parse().unwrap(), 24),
+            "203.0.113.0/24".parse().unwrap()
+        );
+        assert_eq!(
+            Cidr::containing(&"::ffff:203.0.113.200".parse().unwrap(), 64),
+            "203.0.113.200/32".parse().unwrap()
+        );
+
+        assert!("192.0.2.0/33".parse::<Cidr>().is_err());
+        assert!("192.0.2.0/".parse::<Cidr>().is_err());
+        assert!("example.com/24".parse::<Cidr>().is_err());
+    }
+}
@@ -29,6 +29,21 @@ fn create_clap_client_subcommand<'a, 'b>() -> App<'a, 'b> {
             .takes_value(true)
             .required(false)
             .help("Specifies a path to the trusted certificate in PEM format."),
+        Arg::with_name("client-cert")
+            .long("client-cert")
+            .takes_value(true)
+            .required(false)
+            .requires("client-key")
+            .help(
+                "Specifies a path to the client certificate chain in PEM format, for servers \
+                 that authenticate clients.",
+            ),
+        Arg::with_name("client-key")
+            .long("client-key")
+            .takes_value(true)
+            .required(false)
+            .requires("client-cert")
+            .help("Specifies a path to the private key of the client certificate in PEM format."),
         Arg::with_name("ipv4")
             .long("ipv4")
             .short("4")
@@ -2,8 +2,6 @@
 // Copyright (c) 2019, Cloudflare. All rights reserved.
 // See LICENSE for licensing information.
 
-use miscreant::aead;
-use miscreant::aead::Aead;
 use rand::Rng;
 
 use std::convert::TryInto;
@@ -11,6 +9,7 @@ use std::fs::File;
 use std::io;
 use std::io::Read;
 
+use crate::crypto::{Aead, AeadAesSivCmac256};
 use crate::key_rotator::KeyId;
 
 pub const COOKIE_SIZE: usize = 100;
@@ -59,7 +58,7 @@ pub fn make_cookie(keys: NTSKeys, master_key: &[u8], key_id: KeyId) -> Vec<u8> {
     let mut plaintext = [0; 64];
     plaintext[..32].copy_from_slice(&keys.c2s[..32]);
     plaintext[32..64].copy_from_slice(&keys.s2c[..32]);
-    let mut aead = aead::Aes128SivAead::new(master_key);
+    let mut aead = AeadAesSivCmac256::new(master_key);
     let mut ciphertext = aead.seal(&nonce, &[], &plaintext);
     let mut out = Vec::new();
     out.extend(&key_id.to_be_bytes());
@@ -95,7 +94,7 @@ pub fn eat_cookie(cookie: &[u8], key: &[u8]) -> Option<NTSKeys> {
         return None;
     }
     let ciphertext = &cookie[4..];
-    let mut aead = aead::Aes128SivAead::new(key);
+    let mut aead = AeadAesSivCmac256::new(key);
     let answer = aead.open(&ciphertext[0..16], &[], &ciphertext[16..]);
     match answer {
         Err(_) => None,
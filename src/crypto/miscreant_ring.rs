@@ -0,0 +1,60 @@
+// This file is part of cfnts.
+// Copyright (c) 2019, Cloudflare. All rights reserved.
+// See LICENSE for licensing information.
+
+//! Crypto backend using `miscreant` for AES-SIV and `ring` for HMAC.
+
+use ring::hmac;
+
+use super::{Aead, AeadError, HmacSha256Tag};
+
+/// AEAD_AES_SIV_CMAC_256 from RFC 5297.
+// Miscreant calls Aes128SivAead what IANA calls AEAD_AES_SIV_CMAC_256.
+pub struct AeadAesSivCmac256(miscreant::aead::Aes128SivAead);
+
+impl Aead for AeadAesSivCmac256 {
+    fn new(key: &[u8]) -> AeadAesSivCmac256 {
+        AeadAesSivCmac256(miscreant::aead::Aead::new(key))
+    }
+
+    fn seal(&mut self, nonce: &[u8], associated_data: &[u8], plaintext: &[u8]) -> Vec<u8> {
+        miscreant::aead::Aead::seal(&mut self.0, nonce, associated_data, plaintext)
+    }
+
+    fn open(
+        &mut self,
+        nonce: &[u8],
+        associated_data: &[u8],
+        ciphertext: &[u8],
+    ) -> Result<Vec<u8>, AeadError> {
+        miscreant::aead::Aead::open(&mut self.0, nonce, associated_data, ciphertext)
+            .map_err(|_| AeadError)
+    }
+}
+
+/// Encrypt with AES-SIV of RFC 5297 section 2.6, with any number of associated data components.
+/// Only the known-answer tests use it, since `Aead` has just one component before the nonce.
+#[cfg(test)]
+pub fn aes_siv_cmac_256_seal(key: &[u8], headers: &[&[u8]], plaintext: &[u8]) -> Vec<u8> {
+    miscreant::Aes128Siv::new(key).seal(headers, plaintext)
+}
+
+/// Decrypt with AES-SIV of RFC 5297 section 2.7. See `aes_siv_cmac_256_seal`.
+#[cfg(test)]
+pub fn aes_siv_cmac_256_open(
+    key: &[u8],
+    headers: &[&[u8]],
+    ciphertext: &[u8],
+) -> Result<Vec<u8>, AeadError> {
+    miscreant::Aes128Siv::new(key)
+        .open(headers, ciphertext)
+        .map_err(|_| AeadError)
+}
+
+/// Compute HMAC-SHA256 of `data` with `key`.
+pub fn hmac_sha256(key: &[u8], data: &[u8]) -> HmacSha256Tag {
+    let mac_key = hmac::Key::new(hmac::HMAC_SHA256, key);
+    let mut tag = [0; super::HMAC_SHA256_OUTPUT_LEN];
+    tag.copy_from_slice(hmac::sign(&mac_key, data).as_ref());
+    tag
+}
@@ -0,0 +1,236 @@
+// This file is part of cfnts.
+// Copyright (c) 2019, Cloudflare. All rights reserved.
+// See LICENSE for licensing information.
+
+//! Crypto provider interface.
+//!
+//! The protocol code doesn't talk to a crypto library directly. It only uses the `Aead` trait,
+//! `hmac_sha256` and `KeyExporter` from this module. The implementation behind them is a backend
+//! selected with a cargo feature, so a different library can be plugged in without touching
+//! `cookie`, `key_rotator` or `ntp::protocol`.
+//!
+//! Every backend must pass the known-answer tests at the bottom of this file. For them, a backend
+//! also provides `aes_siv_cmac_256_seal` and `aes_siv_cmac_256_open` in test builds, which take
+//! any number of associated data components like RFC 5297 does.
+
+use std::fmt;
+
+// ------------------------------------------------------------------------
+// Backend selection
+// ------------------------------------------------------------------------
+
+#[cfg(feature = "crypto-miscreant-ring")]
+mod miscreant_ring;
+#[cfg(feature = "crypto-miscreant-ring")]
+use self::miscreant_ring as backend;
+
+#[cfg(not(feature = "crypto-miscreant-ring"))]
+compile_error!("no crypto backend is selected. Please enable the `crypto-miscreant-ring` feature.");
+
+pub use self::backend::{hmac_sha256, AeadAesSivCmac256};
+
+// ------------------------------------------------------------------------
+// Interface
+// ------------------------------------------------------------------------
+
+/// The output length of HMAC-SHA256 in bytes.
+pub const HMAC_SHA256_OUTPUT_LEN: usize = 32;
+
+/// HMAC-SHA256 tag.
+pub type HmacSha256Tag = [u8; HMAC_SHA256_OUTPUT_LEN];
+
+/// Error returned when an AEAD ciphertext cannot be authenticated.
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+pub struct AeadError;
+
+impl std::error::Error for AeadError {}
+
+impl fmt::Display for AeadError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "AEAD authentication failed")
+    }
+}
+
+/// An Authenticated Encryption with Associated Data algorithm.
+///
+/// The nonce is treated as a separate associated data component, as described in RFC 5297
+/// section 3.
+pub trait Aead {
+    /// Create a new AEAD instance.
+    ///
+    /// # Panics
+    ///
+    /// If the key has the wrong length for the algorithm.
+    ///
+    fn new(key: &[u8]) -> Self;
+
+    /// Encrypt and authenticate the plaintext. The returned ciphertext includes the tag.
+    fn seal(&mut self, nonce: &[u8], associated_data: &[u8], plaintext: &[u8]) -> Vec<u8>;
+
+    /// Authenticate and decrypt the ciphertext.
+    ///
+    /// # Errors
+    ///
+    /// There will be an error if the ciphertext or the associated data is not authentic.
+    ///
+    fn open(
+        &mut self,
+        nonce: &[u8],
+        associated_data: &[u8],
+        ciphertext: &[u8],
+    ) -> Result<Vec<u8>, AeadError>;
+}
+
+/// Keying material exporter of a TLS session (RFC 5705 and RFC 8446 section 7.5).
+pub trait KeyExporter {
+    /// The error returned from the TLS library.
+    type Error: std::error::Error;
+
+    /// Fill the whole `output` with keying material derived from the `label` and `context`.
+    fn export_keying_material(
+        &self,
+        output: &mut [u8],
+        label: &[u8],
+        context: Option<&[u8]>,
+    ) -> Result<(), Self::Error>;
+}
+
+impl<T: rustls::Session> KeyExporter for T {
+    type Error = rustls::TLSError;
+
+    fn export_keying_material(
+        &self,
+        output: &mut [u8],
+        label: &[u8],
+        context: Option<&[u8]>,
+    ) -> Result<(), rustls::TLSError> {
+        rustls::Session::export_keying_material(self, output, label, context)
+    }
+}
+
+// ------------------------------------------------------------------------
+// Tests
+// ------------------------------------------------------------------------
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn from_hex(hex: &str) -> Vec<u8> {
+        (0..hex.len())
+            .step_by(2)
+            .map(|i| u8::from_str_radix(&hex[i..i + 2], 16).unwrap())
+            .collect()
+    }
+
+    struct SivVector {
+        key: &'static str,
+        headers: &'static [&'static str],
+        plaintext: &'static str,
+        ciphertext: &'static str,
+    }
+
+    // The examples from RFC 5297 appendix A.1 and A.2. The ciphertexts are the IV followed by the
+    // encrypted plaintext. In A.2, the last header is the nonce.
+    const AES_SIV_CMAC_256_VECTORS: &[SivVector] = &[
+        SivVector {
+            key: "fffefdfcfbfaf9f8f7f6f5f4f3f2f1f0f0f1f2f3f4f5f6f7f8f9fafbfcfdfeff",
+            headers: &["101112131415161718191a1b1c1d1e1f2021222324252627"],
+            plaintext: "112233445566778899aabbccddee",
+            ciphertext: "85632d07c6e8f37f950acd320a2ecc9340c02b9690c4dc04daef7f6afe5c",
+        },
+        SivVector {
+            key: "7f7e7d7c7b7a79787776757473727170404142434445464748494a4b4c4d4e4f",
+            headers: &[
+                "00112233445566778899aabbccddeeffdeaddadadeaddadaffeeddccbbaa99887766554433221100",
+                "102030405060708090a0",
+                "09f911029d74e35bd84156c5635688c0",
+            ],
+            plaintext: "7468697320697320736f6d6520706c61696e7465787420746f20656e637279\
+                        7074207573696e67205349562d414553",
+            ciphertext: "7bdb6e3b432667eb06f4d14bff2fbd0fcb900f2fddbe404326601965c889bf17dba77c\
+                         eb094fa663b7a3f748ba8af829ea64ad544a272e9c485b62a3fd5c0d",
+        },
+    ];
+
+    #[test]
+    fn test_aes_siv_cmac_256_known_answers() {
+        for vector in AES_SIV_CMAC_256_VECTORS {
+            let key = from_hex(vector.key);
+            let headers: Vec<Vec<u8>> = vector.headers.iter().map(|h| from_hex(h)).collect();
+            let headers: Vec<&[u8]> = headers.iter().map(Vec::as_slice).collect();
+            let plaintext = from_hex(vector.plaintext);
+            let ciphertext = from_hex(vector.ciphertext);
+
+            assert_eq!(
+                backend::aes_siv_cmac_256_seal(&key, &headers, &plaintext),
+                ciphertext
+            );
+            assert_eq!(
+                backend::aes_siv_cmac_256_open(&key, &headers, &ciphertext),
+                Ok(plaintext)
+            );
+
+            let mut mangled = ciphertext.clone();
+            mangled[0] ^= 1;
+            assert_eq!(
+                backend::aes_siv_cmac_256_open(&key, &headers, &mangled),
+                Err(AeadError)
+            );
+        }
+    }
+
+    #[test]
+    fn test_aead_aes_siv_cmac_256() {
+        for vector in AES_SIV_CMAC_256_VECTORS {
+            let key = from_hex(vector.key);
+            let ad = from_hex(vector.headers[0]);
+            let nonce = from_hex("09f911029d74e35bd84156c5635688c0");
+            let plaintext = from_hex(vector.plaintext);
+            let mut aead = AeadAesSivCmac256::new(&key);
+
+            // RFC 5297 section 3 passes the nonce as the last associated data component.
+            let ciphertext = aead.seal(&nonce, &ad, &plaintext);
+            assert_eq!(
+                ciphertext,
+                backend::aes_siv_cmac_256_seal(&key, &[&ad, &nonce], &plaintext)
+            );
+            assert_eq!(aead.open(&nonce, &ad, &ciphertext).unwrap(), plaintext);
+
+            // Any modification to the ciphertext, the nonce, or the associated data must be
+            // detected.
+            let mut mangled = ciphertext.clone();
+            mangled[0] ^= 1;
+            assert_eq!(aead.open(&nonce, &ad, &mangled), Err(AeadError));
+
+            let mut mangled_nonce = nonce.clone();
+            mangled_nonce[0] ^= 1;
+            assert_eq!(aead.open(&mangled_nonce, &ad, &ciphertext), Err(AeadError));
+
+            let mut mangled_ad = ad.clone();
+            mangled_ad.push(0);
+            assert_eq!(aead.open(&nonce, &mangled_ad, &ciphertext), Err(AeadError));
+        }
+    }
+
+    #[test]
+    fn test_hmac_sha256_known_answers() {
+        // Test cases 1 and 2 from RFC 4231.
+        let vectors = [
+            (
+                from_hex("0b0b0b0b0b0b0b0b0b0b0b0b0b0b0b0b0b0b0b0b"),
+                Vec::from("Hi There"),
+                "b0344c61d8db38535ca8afceaf0bf12b881dc200c9833da726e9376c2e32cff7",
+            ),
+            (
+                Vec::from("Jefe"),
+                Vec::from("what do ya want for nothing?"),
+                "5bdcc146bf60754e6a042426089575c75a003f089d2739839dec58b964ec3843",
+            ),
+        ];
+
+        for (key, data, tag) in vectors.iter() {
+            assert_eq!(&hmac_sha256(key, data)[..], &from_hex(tag)[..]);
+        }
+    }
+}
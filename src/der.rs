@@ -0,0 +1,172 @@
+// This file is part of cfnts.
+// Copyright (c) 2019, Cloudflare. All rights reserved.
+// See LICENSE for licensing information.
+
+//! Minimal DER encoding and decoding.
+//!
+//! Only definite lengths of up to four bytes are supported, which is enough for certificates and
+//! private keys. See ITU-T X.690 section 8 and 10 for the encoding.
+
+use std::convert::TryFrom;
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+
+pub const TAG_INTEGER: u8 = 0x02;
+pub const TAG_OCTET_STRING: u8 = 0x04;
+pub const TAG_OID: u8 = 0x06;
+pub const TAG_SEQUENCE: u8 = 0x30;
+pub const TAG_SET: u8 = 0x31;
+pub const TAG_UTC_TIME: u8 = 0x17;
+pub const TAG_GENERALIZED_TIME: u8 = 0x18;
+
+/// Split the first DER element off `input` and return its tag, its content, and the rest.
+pub fn read_element(input: &[u8]) -> Option<(u8, &[u8], &[u8])> {
+    let (&tag, input) = input.split_first()?;
+    let (&first, input) = input.split_first()?;
+
+    let (len, input) = if first < 0x80 {
+        (first as usize, input)
+    } else {
+        // In the long form, the low bits are the number of the length bytes.
+        let count = (first & 0x7f) as usize;
+        if count == 0 || count > 4 || input.len() < count {
+            return None;
+        }
+        let len = input[..count]
+            .iter()
+            .fold(0, |len, &byte| (len << 8) | byte as usize);
+        (len, &input[count..])
+    };
+
+    if input.len() < len {
+        return None;
+    }
+    Some((tag, &input[..len], &input[len..]))
+}
+
+/// Split the first DER element off `input`, if it has the expected tag, and return its content
+/// and the rest.
+pub fn expect_element(input: &[u8], expected: u8) -> Option<(&[u8], &[u8])> {
+    match read_element(input)? {
+        (tag, content, rest) if tag == expected => Some((content, rest)),
+        _ => None,
+    }
+}
+
+/// Encode an element with `tag` and `content`.
+pub fn write_element(tag: u8, content: &[u8]) -> Vec<u8> {
+    let mut output = vec![tag];
+    if content.len() < 0x80 {
+        output.push(content.len() as u8);
+    } else {
+        // Use as few length bytes as possible, as DER requires.
+        let len_bytes = content.len().to_be_bytes();
+        let skip = len_bytes.iter().take_while(|&&byte| byte == 0).count();
+        output.push(0x80 | (len_bytes.len() - skip) as u8);
+        output.extend_from_slice(&len_bytes[skip..]);
+    }
+    output.extend_from_slice(content);
+    output
+}
+
+/// Return the number of days from 1970-01-01 to a date in the proleptic Gregorian calendar.
+// This is the days_from_civil algorithm from http://howardhinnant.github.io/date_algorithms.html.
+fn days_from_civil(year: i64, month: i64, day: i64) -> i64 {
+    let year = if month <= 2 { year - 1 } else { year };
+    let era = year.div_euclid(400);
+    let year_of_era = year - era * 400;
+    let day_of_year = (153 * ((month + 9) % 12) + 2) / 5 + day - 1;
+    let day_of_era = year_of_era * 365 + year_of_era / 4 - year_of_era / 100 + day_of_year;
+    era * 146_097 + day_of_era - 719_468
+}
+
+/// Parse the content of a UTCTime or a GeneralizedTime in UTC, like "491231235959Z" or
+/// "20491231235959Z". Fractions of seconds are ignored. Return `None` if the time is malformed or
+/// before 1970.
+pub fn parse_time(tag: u8, content: &[u8]) -> Option<SystemTime> {
+    let text = std::str::from_utf8(content).ok()?.strip_suffix('Z')?;
+    // Only GeneralizedTime may have fractions of seconds.
+    let text = match text.find('.') {
+        Some(dot) if tag == TAG_GENERALIZED_TIME => &text[..dot],
+        _ => text,
+    };
+    if !text.bytes().all(|byte| byte.is_ascii_digit()) {
+        return None;
+    }
+
+    let (year, rest) = match (tag, text.len()) {
+        (TAG_UTC_TIME, 12) => {
+            // Two-digit years mean 1950 to 2049, as RFC 5280 section 4.1.2.5.1 says.
+            let year: i64 = text[..2].parse().ok()?;
+            (
+                if year < 50 { 2000 + year } else { 1900 + year },
+                &text[2..],
+            )
+        }
+        (TAG_GENERALIZED_TIME, 14) => (text[..4].parse().ok()?, &text[4..]),
+        _ => return None,
+    };
+    let field = |index: usize| rest[index..index + 2].parse::<i64>().ok();
+    let (month, day) = (field(0)?, field(2)?);
+    let (hour, minute, second) = (field(4)?, field(6)?, field(8)?);
+    if !(1..=12).contains(&month) || !(1..=31).contains(&day) || hour > 23 || minute > 59 {
+        return None;
+    }
+    // A leap second is allowed.
+    if second > 60 {
+        return None;
+    }
+
+    let days = days_from_civil(year, month, day);
+    let seconds = days * 86_400 + hour * 3_600 + minute * 60 + second;
+    let seconds = u64::try_from(seconds).ok()?;
+    UNIX_EPOCH.checked_add(Duration::from_secs(seconds))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_round_trip() {
+        for len in &[0, 1, 0x7f, 0x80, 0xff, 0x100, 0x10000] {
+            let content = vec![0xab; *len];
+            let mut encoded = write_element(TAG_OCTET_STRING, &content);
+            encoded.push(0xcd);
+
+            assert_eq!(
+                read_element(&encoded),
+                Some((TAG_OCTET_STRING, &content[..], &[0xcd][..]))
+            );
+            assert_eq!(expect_element(&encoded, TAG_SEQUENCE), None);
+            assert_eq!(read_element(&encoded[..encoded.len() - 2]), None);
+        }
+        assert_eq!(
+            write_element(TAG_INTEGER, &[0; 0x80])[..3],
+            [0x02, 0x81, 0x80]
+        );
+    }
+
+    #[test]
+    fn test_parse_time() {
+        let at = |seconds| Some(UNIX_EPOCH + Duration::from_secs(seconds));
+
+        assert_eq!(parse_time(TAG_UTC_TIME, b"700101000000Z"), at(0));
+        assert_eq!(
+            parse_time(TAG_UTC_TIME, b"490101000000Z"),
+            at(2_493_072_000)
+        );
+        assert_eq!(
+            parse_time(TAG_GENERALIZED_TIME, b"20000229123456Z"),
+            at(951_827_696)
+        );
+        assert_eq!(
+            parse_time(TAG_GENERALIZED_TIME, b"20000229123456.789Z"),
+            at(951_827_696)
+        );
+
+        assert_eq!(parse_time(TAG_UTC_TIME, b"20000229123456Z"), None);
+        assert_eq!(parse_time(TAG_GENERALIZED_TIME, b"20001329123456Z"), None);
+        assert_eq!(parse_time(TAG_GENERALIZED_TIME, b"20000229123456"), None);
+        assert_eq!(parse_time(TAG_GENERALIZED_TIME, b"19691231235959Z"), None);
+    }
+}
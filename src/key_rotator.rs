@@ -11,8 +11,6 @@ use memcache::MemcacheError;
 
 use prometheus::{opts, register_counter, register_int_counter, IntCounter};
 
-use ring::hmac;
-
 use std::collections::HashMap;
 use std::sync::{Arc, RwLock};
 use std::thread;
@@ -21,6 +19,7 @@ use std::time::SystemTime;
 use std::time::{Duration, UNIX_EPOCH};
 
 use crate::cookie::CookieKey;
+use crate::crypto::{hmac_sha256, HmacSha256Tag};
 
 lazy_static! {
     static ref ROTATION_COUNTER: IntCounter =
@@ -107,7 +106,7 @@ pub struct KeyRotator {
     latest_key_id: KeyId,
 
     /// Cache store.
-    cache: HashMap<KeyId, hmac::Tag>,
+    cache: HashMap<KeyId, HmacSha256Tag>,
 
     /// Logger.
     // TODO: since we don't use the logger now, I will put an `allow(dead_code)` here first. I will
@@ -239,10 +238,8 @@ impl KeyRotator {
     /// Add an entry to the cache.
     // It should be private. Don't make it public.
     fn cache_insert(&mut self, key_id: KeyId, value: &[u8]) {
-        // Create a MAC key.
-        let mac_key = hmac::Key::new(hmac::HMAC_SHA256, self.master_key.as_bytes());
-        // Generating a MAC tag with a MAC key.
-        let tag = hmac::sign(&mac_key, value);
+        // Generating a MAC tag with the master key.
+        let tag = hmac_sha256(self.master_key.as_bytes(), value);
 
         self.cache.insert(key_id, tag);
     }
@@ -254,15 +251,20 @@ impl KeyRotator {
     }
 
     /// Return the latest key id and hmac tag of the rotator.
-    pub fn latest_key_value(&self) -> (KeyId, &hmac::Tag) {
+    pub fn latest_key_value(&self) -> (KeyId, &HmacSha256Tag) {
         // This unwrap cannot panic because the HashMap will always contain the latest key id.
         (self.latest_key_id, self.get(self.latest_key_id).unwrap())
     }
 
     /// Return an entry in the cache using a key id.
-    pub fn get(&self, key_id: KeyId) -> Option<&hmac::Tag> {
+    pub fn get(&self, key_id: KeyId) -> Option<&HmacSha256Tag> {
         self.cache.get(&key_id)
     }
+
+    /// Return how long in seconds a key stays in the cache after its period starts.
+    pub fn retention(&self) -> u64 {
+        self.duration * (self.number_of_backward_periods + 1)
+    }
 }
 
 pub fn periodic_rotate(rotor: Arc<RwLock<KeyRotator>>) {
@@ -11,14 +11,20 @@ extern crate slog_stdlog;
 extern crate sloggers;
 
 mod cfsock;
+mod cidr;
 mod cmd;
 mod cookie;
+mod crypto;
+mod der;
 mod error;
 mod key_rotator;
 mod metrics;
 mod ntp;
 mod nts_ke;
+mod private_key;
+mod signal;
 mod sub_command;
+mod x509;
 
 use sloggers::terminal::{Destination, TerminalLoggerBuilder};
 use sloggers::types::Severity;
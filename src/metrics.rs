@@ -4,6 +4,7 @@ use prometheus::{self, register_int_gauge, Encoder, __register_gauge, labels, op
 use std::io;
 use std::io::{BufRead, BufReader, Write};
 use std::net;
+use std::sync::atomic::{AtomicBool, Ordering};
 use std::thread;
 
 use slog::error;
@@ -16,6 +17,18 @@ pub struct MetricsConfig {
 
 const VERSION: &str = env!("CARGO_PKG_VERSION");
 
+/// The path of the readiness endpoint. All other paths serve metrics.
+const READY_PATH: &str = "/ready";
+
+/// Whether the server wants new clients. It's false before the server starts and while it shuts
+/// down.
+static READY: AtomicBool = AtomicBool::new(false);
+
+/// Set whether the server wants new clients. It's reported at the readiness endpoint.
+pub fn set_ready(ready: bool) {
+    READY.store(ready, Ordering::SeqCst);
+}
+
 lazy_static! {
     static ref VERSION_INFO: prometheus::IntGauge = register_int_gauge!(opts!(
         "build_info",
@@ -27,9 +40,15 @@ lazy_static! {
     .unwrap();
 }
 
-fn wait_for_req_or_eof(dest: &net::TcpStream, logger: slog::Logger) -> Result<(), io::Error> {
+/// Wait for the end of the request and return its path, or `None` if we got EOF before the
+/// request line.
+fn wait_for_req_or_eof(
+    dest: &net::TcpStream,
+    logger: slog::Logger,
+) -> Result<Option<String>, io::Error> {
     let mut reader = BufReader::new(dest);
     let mut req_line = String::new();
+    let mut path = None;
     let mut done = false;
     while !done {
         req_line.clear();
@@ -46,8 +65,12 @@ fn wait_for_req_or_eof(dest: &net::TcpStream, logger: slog::Logger) -> Result<()
         if req_line == "\r\n" {
             done = true; // terminates the request
         }
+        if path.is_none() && !req_line.is_empty() {
+            // The request line looks like "GET /path HTTP/1.1".
+            path = Some(req_line.split_whitespace().nth(1).unwrap_or("").to_owned());
+        }
     }
-    Ok(())
+    Ok(path)
 }
 
 fn scrape_result() -> String {
@@ -59,18 +82,34 @@ fn scrape_result() -> String {
         + &String::from_utf8(buffer).unwrap()
 }
 
+fn ready_result() -> String {
+    if READY.load(Ordering::SeqCst) {
+        "HTTP/1.1 200 OK\r\nContent-Type: text/plain\r\n\r\nready\n".to_owned()
+    } else {
+        "HTTP/1.1 503 Service Unavailable\r\nContent-Type: text/plain\r\n\r\nnot ready\n".to_owned()
+    }
+}
+
 fn serve_metrics(mut dest: net::TcpStream, logger: slog::Logger) {
-    if let Err(e) = wait_for_req_or_eof(&dest, logger.clone()) {
-        error!(
-            logger,
-            "error in wait_for_req_or_eof: {:?}, unable to serve metrics", e
-        );
-        if let Err(e) = dest.shutdown(net::Shutdown::Both) {
-            error!(logger, "shutting down TcpStream failed with error: {:?}", e);
+    let path = match wait_for_req_or_eof(&dest, logger.clone()) {
+        Ok(path) => path,
+        Err(e) => {
+            error!(
+                logger,
+                "error in wait_for_req_or_eof: {:?}, unable to serve metrics", e
+            );
+            if let Err(e) = dest.shutdown(net::Shutdown::Both) {
+                error!(logger, "shutting down TcpStream failed with error: {:?}", e);
+            }
+            return;
         }
-        return;
-    }
-    if let Err(e) = dest.write(scrape_result().as_bytes()) {
+    };
+    let result = if path.as_deref() == Some(READY_PATH) {
+        ready_result()
+    } else {
+        scrape_result()
+    };
+    if let Err(e) = dest.write(result.as_bytes()) {
         error!(
             logger,
             "write to TcpStream failed with error: {:?}, unable to serve metrics", e
@@ -1,7 +1,6 @@
+use crate::crypto::{Aead, AeadAesSivCmac256};
 use crate::nts_ke::client::NtsKeResult;
 
-use miscreant::aead::Aead;
-use miscreant::aead::Aes128SivAead;
 use rand::Rng;
 use slog::debug;
 use std::error::Error;
@@ -116,8 +115,8 @@ pub fn run_nts_ntp_client(
     let socket = socket.unwrap();
     socket.set_read_timeout(Some(TIMEOUT))?;
     socket.set_write_timeout(Some(TIMEOUT))?;
-    let mut send_aead = Aes128SivAead::new(&state.keys.c2s);
-    let mut recv_aead = Aes128SivAead::new(&state.keys.s2c);
+    let mut send_aead = AeadAesSivCmac256::new(&state.keys.c2s);
+    let mut recv_aead = AeadAesSivCmac256::new(&state.keys.s2c);
     let header = NtpPacketHeader {
         leap_indicator: LeapState::NoLeap,
         version: 4,
@@ -151,7 +150,7 @@ pub fn run_nts_ntp_client(
         auth_enc_exts: vec![],
     };
     socket.connect(addr.unwrap())?;
-    let wire_packet = &serialize_nts_packet::<Aes128SivAead>(packet, &mut send_aead);
+    let wire_packet = &serialize_nts_packet::<AeadAesSivCmac256>(packet, &mut send_aead);
     let t1 = system_to_ntpfloat(SystemTime::now());
     socket.send(wire_packet)?;
     debug!(logger, "transmitting packet");
@@ -159,7 +158,7 @@ pub fn run_nts_ntp_client(
     let (size, _origin) = socket.recv_from(&mut buff)?;
     let t4 = system_to_ntpfloat(SystemTime::now());
     debug!(logger, "received packet");
-    let received = parse_nts_packet::<Aes128SivAead>(&buff[0..size], &mut recv_aead);
+    let received = parse_nts_packet::<AeadAesSivCmac256>(&buff[0..size], &mut recv_aead);
     match received {
         Err(x) => Err(Box::new(x)),
         Ok(packet) => {
@@ -1,10 +1,11 @@
 use byteorder::{BigEndian, ReadBytesExt, WriteBytesExt};
-use miscreant::aead::Aead;
 use rand::Rng;
 
 use std::io::{Cursor, Error, ErrorKind, Read, Write};
 use std::panic;
 
+use crate::crypto::Aead;
+
 use self::LeapState::*;
 use self::NtpExtensionType::*;
 use self::PacketMode::*;
@@ -417,7 +418,7 @@ pub fn serialize_nts_packet<T: Aead>(packet: NtsPacket, encryptor: &mut T) -> Ve
 #[cfg(test)]
 mod tests {
     use super::*;
-    use miscreant::aead::Aes128SivAead;
+    use crate::crypto::AeadAesSivCmac256;
     #[test]
     fn test_ntp_header_parse() {
         let leaps = vec![NoLeap, Positive, Negative, LeapState::Unknown];
@@ -481,7 +482,7 @@ mod tests {
     #[test]
     fn test_nts_parse() {
         let key = [0; 32];
-        let mut test_aead = Aes128SivAead::new(&key);
+        let mut test_aead = AeadAesSivCmac256::new(&key);
         let header = NtpPacketHeader {
             leap_indicator: NoLeap,
             version: 4,
@@ -515,6 +516,6 @@ mod tests {
                 contents: vec![0xfe; 32],
             }],
         };
-        roundtrip_test::<Aes128SivAead>(packet, &mut test_aead);
+        roundtrip_test::<AeadAesSivCmac256>(packet, &mut test_aead);
     }
 }
@@ -7,6 +7,7 @@
 use sloggers::terminal::TerminalLoggerBuilder;
 use sloggers::Build;
 
+use std::collections::HashMap;
 use std::convert::TryFrom;
 use std::net::{IpAddr, SocketAddr};
 use std::str::FromStr;
@@ -35,6 +36,10 @@ pub struct NtpServerConfig {
     // Each of the elements can be either IPv4 or IPv6 address. It cannot be a UNIX socket address.
     addrs: Vec<SocketAddr>,
 
+    /// Names of the sockets passed with systemd socket activation for specific listening
+    /// addresses. Other addresses are matched to the passed sockets by the address.
+    socket_names: HashMap<SocketAddr, String>,
+
     pub cookie_key: CookieKey,
 
     /// The logger that will be used throughout the application, while the server is running.
@@ -44,6 +49,10 @@ pub struct NtpServerConfig {
     pub memcached_url: String,
     pub metrics_config: Option<MetricsConfig>,
     pub upstream_addr: Option<SocketAddr>,
+
+    /// Seconds to keep answering queries after a shutdown is requested, while the readiness
+    /// endpoint reports not ready, so that clients can be moved away before we stop.
+    pub shutdown_drain_period: u64,
 }
 
 /// We decided to make NtpServerConfig mutable so that you can add more address after you parse
@@ -59,6 +68,7 @@ impl NtpServerConfig {
     ) -> NtpServerConfig {
         NtpServerConfig {
             addrs: Vec::new(),
+            socket_names: HashMap::new(),
 
             // Use terminal logger as a default logger. The users can override it using
             // `set_logger` later, if they want.
@@ -69,6 +79,8 @@ impl NtpServerConfig {
                 .build()
                 .expect("BUG: TerminalLoggerBuilder::build shouldn't return an error."),
 
+            shutdown_drain_period: 5,
+
             // From parameters.
             cookie_key,
             memcached_url,
@@ -87,6 +99,17 @@ impl NtpServerConfig {
         self.addrs.as_slice()
     }
 
+    /// Set the name of the socket passed with systemd socket activation for a listening address.
+    pub fn set_socket_name(&mut self, addr: SocketAddr, name: String) {
+        self.socket_names.insert(addr, name);
+    }
+
+    /// Return the name of the socket passed with systemd socket activation for a listening
+    /// address, if it's set.
+    pub fn socket_name(&self, addr: &SocketAddr) -> Option<&str> {
+        self.socket_names.get(addr).map(String::as_str)
+    }
+
     /// Set a new logger to the config.
     pub fn set_logger(&mut self, logger: slog::Logger) {
         self.logger = logger;
@@ -114,6 +137,8 @@ impl NtpServerConfig {
     /// following cases:
     ///
     /// * The upstream port in the configuration file is a valid `i64` but not a valid `u16`.
+    /// * The shutdown drain period in the configuration file is a valid `i64` but not a valid
+    ///   `u64`.
     ///
     // Returning a `Message` object here is not a good practice. I will figure out a good practice
     // later.
@@ -173,6 +198,21 @@ impl NtpServerConfig {
                 None
             };
 
+        let shutdown_drain_period = match settings.get_int("shutdown_drain_period") {
+            // If it's a not-found error, we just keep the default value.
+            Err(config::ConfigError::NotFound(_)) => None,
+            Err(error) => return Err(error),
+            Ok(val) => match u64::try_from(val) {
+                Ok(val) => Some(val),
+                // The error will happen when the period is not in a range of `u64`.
+                Err(_) => {
+                    return Err(config::ConfigError::Message(String::from(
+                        "the shutdown drain period is not a valid u64",
+                    )));
+                }
+            },
+        };
+
         // Note that all of the file reading stuffs should be at the end of the function so that
         // all the not-file-related stuffs can fail fast.
 
@@ -185,12 +225,30 @@ impl NtpServerConfig {
             metrics_config,
             upstream_sock_addr,
         );
+        if let Some(shutdown_drain_period) = shutdown_drain_period {
+            config.shutdown_drain_period = shutdown_drain_period;
+        }
 
         let addrs = settings.get_array("addr")?;
         for addr in addrs {
-            // Parse SocketAddr from a string.
-            let sock_addr = addr.to_string().parse().wrap_err()?;
-            config.add_address(sock_addr);
+            // An address can be either a plain string or a table with the name of its socket.
+            match addr.clone().into_table() {
+                Ok(mut table) => {
+                    let sock_addr: SocketAddr = match table.remove("addr") {
+                        Some(value) => value.into_str()?.parse().wrap_err()?,
+                        None => return Err(config::ConfigError::NotFound(String::from("addr"))),
+                    };
+                    if let Some(name) = table.remove("socket_name") {
+                        config.set_socket_name(sock_addr, name.into_str()?);
+                    }
+                    config.add_address(sock_addr);
+                }
+                Err(_) => {
+                    // Parse SocketAddr from a string.
+                    let sock_addr = addr.to_string().parse().wrap_err()?;
+                    config.add_address(sock_addr);
+                }
+            }
         }
 
         Ok(config)
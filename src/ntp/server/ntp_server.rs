@@ -1,17 +1,20 @@
 use super::config::NtpServerConfig;
 use crate::cfsock;
 use crate::cookie::{eat_cookie, get_keyid, make_cookie, NTSKeys, COOKIE_SIZE};
+use crate::crypto::{Aead, AeadAesSivCmac256};
 use crate::key_rotator::{periodic_rotate, KeyRotator};
 use crate::metrics;
+use crate::signal;
 
 use lazy_static::lazy_static;
 use prometheus::{opts, register_counter, register_int_counter, IntCounter};
-use slog::{error, info};
+use slog::{error, info, warn};
+use socket2::Type;
 
 use std::io::{Error, ErrorKind};
 use std::net::{SocketAddr, ToSocketAddrs, UdpSocket};
 use std::os::unix::io::AsRawFd;
-use std::sync::{Arc, RwLock};
+use std::sync::{mpsc, Arc, RwLock};
 use std::thread;
 use std::time;
 use std::time::{Duration, SystemTime};
@@ -19,9 +22,6 @@ use std::vec;
 
 use crossbeam::sync::WaitGroup;
 use libc::{in6_pktinfo, in_pktinfo};
-/// Miscreant calls Aes128SivAead what IANA calls AEAD_AES_SIV_CMAC_256
-use miscreant::aead::Aead;
-use miscreant::aead::Aes128SivAead;
 use nix::sys::socket::{
     recvmsg, sendmsg, setsockopt, sockopt, CmsgSpace, ControlMessage, MsgFlags,
 };
@@ -40,6 +40,9 @@ const BUF_SIZE: usize = 1280; // Anything larger might fragment.
 const TWO_POW_32: f64 = 4294967296.0;
 const TWO_POW_16: f64 = 65536.0;
 
+/// How long we wait for the servers before checking whether a shutdown is requested.
+const SHUTDOWN_CHECK_INTERVAL: Duration = Duration::from_millis(500);
+
 lazy_static! {
     static ref QUERY_COUNTER: IntCounter =
         register_int_counter!("ntp_queries_total", "Number of NTP queries").unwrap();
@@ -257,25 +260,84 @@ pub fn start_ntp_server(config: NtpServerConfig) -> Result<(), Box<dyn std::erro
         });
     }
 
+    // The sockets that systemd has opened for us, if the server is socket-activated. The
+    // addresses without one bind their own sockets.
+    let mut inherited_sockets = cfsock::inherited_sockets()?;
+
     let wg = WaitGroup::new();
     for addr in config.addrs() {
         let addr = addr.to_socket_addrs().unwrap().next().unwrap();
-        let socket = cfsock::udp_listen(&addr)?;
+        let inherited = cfsock::take_inherited_socket(
+            &mut inherited_sockets,
+            Type::DGRAM,
+            &addr,
+            config.socket_name(&addr),
+        )?;
+        let inherited_socket = inherited.is_some();
+        let socket = match inherited {
+            Some(socket) => socket.into(),
+            None => cfsock::udp_listen(&addr)?,
+        };
         let wg = wg.clone();
         let logger = logger.new(slog::o!("listen_addr"=>addr));
         let keys = keys.clone();
         let servstate = servstate.clone();
-        info!(logger, "Listening on: {}", socket.local_addr()?);
-        let mut use_ipv4 = true;
-        if let SocketAddr::V6(_) = addr {
-            use_ipv4 = false;
-        }
+        let local_addr = socket.local_addr()?;
+        info!(logger, "Listening on: {}", local_addr; "inherited_socket" => inherited_socket);
+        // An inherited socket may be matched by name, so its family is not always the one of the
+        // configured address.
+        let use_ipv4 = local_addr.is_ipv4();
         thread::spawn(move || {
             run_server(socket, keys, servstate, logger, use_ipv4).expect("server could not be run");
             drop(wg);
         });
     }
-    wg.wait();
+
+    for socket in inherited_sockets {
+        warn!(
+            logger,
+            "closing an inherited socket that no address is configured for";
+            "name" => socket.name,
+            "addr" => socket.addr.map(|addr| addr.to_string())
+        );
+    }
+
+    // All the addresses are bound, so we are ready for clients.
+    metrics::set_ready(true);
+
+    // Stop gracefully on SIGTERM or SIGINT.
+    if let Err(error) = signal::install_shutdown_handler() {
+        warn!(logger, "cannot install the shutdown handler: {}", error);
+    }
+
+    // Wait for the servers in another thread, so that we can return if all of them fail.
+    let (done_sender, done_receiver) = mpsc::channel();
+    thread::spawn(move || {
+        wg.wait();
+        let _ = done_sender.send(());
+    });
+
+    loop {
+        match done_receiver.recv_timeout(SHUTDOWN_CHECK_INTERVAL) {
+            Err(mpsc::RecvTimeoutError::Timeout) => {
+                if signal::shutdown_requested() {
+                    break;
+                }
+            }
+            // All the servers have stopped.
+            Ok(()) | Err(mpsc::RecvTimeoutError::Disconnected) => return Ok(()),
+        }
+    }
+
+    // Keep answering while the readiness endpoint tells the load balancers to move clients away.
+    info!(
+        logger,
+        "shutdown requested, draining for {} seconds", config.shutdown_drain_period
+    );
+    metrics::set_ready(false);
+    thread::sleep(Duration::from_secs(config.shutdown_drain_period));
+
+    info!(logger, "NTP server is shut down");
     Ok(())
 }
 
@@ -398,9 +460,9 @@ fn process_nts(
     cookie_keys: Arc<RwLock<KeyRotator>>,
     query_raw: &[u8],
 ) -> Vec<u8> {
-    let mut recv_aead = Aes128SivAead::new(&keys.c2s);
-    let mut send_aead = Aes128SivAead::new(&keys.s2c);
-    let query = parse_nts_packet::<Aes128SivAead>(query_raw, &mut recv_aead);
+    let mut recv_aead = AeadAesSivCmac256::new(&keys.c2s);
+    let mut send_aead = AeadAesSivCmac256::new(&keys.s2c);
+    let query = parse_nts_packet::<AeadAesSivCmac256>(query_raw, &mut recv_aead);
     match query {
         Ok(packet) => serialize_nts_packet(
             nts_response(packet, resp_header, keys, cookie_keys),
@@ -1,4 +1,4 @@
-use slog::{debug, info};
+use slog::{debug, info, warn};
 use std::error::Error;
 use std::io::{Read, Write};
 use std::net::{Shutdown, TcpStream, ToSocketAddrs};
@@ -31,11 +31,11 @@ use crate::nts_ke::records::{
     NextProtocolRecord,
     NtsKeParseError,
     Party,
-
     // Structs.
     ReceivedNtsKeRecordState,
 
     // Constants.
+    ALPN_PROTOCOL,
     HEADER_SIZE,
 };
 use crate::sub_command::client::ClientConfig;
@@ -44,7 +44,6 @@ type Cookie = Vec<u8>;
 
 const DEFAULT_NTP_PORT: u16 = 123;
 const DEFAULT_KE_PORT: u16 = 4460;
-const DEFAULT_SCHEME: u16 = 0;
 const TIMEOUT: Duration = Duration::from_secs(15);
 
 #[derive(Clone, Debug)]
@@ -64,9 +63,9 @@ pub fn run_nts_ke_client(
     client_config: ClientConfig,
 ) -> Result<NtsKeResult, Box<dyn Error>> {
     let mut tls_config = rustls::ClientConfig::new();
-    let alpn_proto = String::from("ntske/1");
-    let alpn_bytes = alpn_proto.into_bytes();
-    tls_config.set_protocols(&[alpn_bytes]);
+    tls_config.set_protocols(&[Vec::from(ALPN_PROTOCOL)]);
+    // NTS-KE requires TLS 1.3.
+    tls_config.versions = vec![rustls::ProtocolVersion::TLSv1_3];
 
     match client_config.trusted_cert {
         Some(cert) => {
@@ -80,6 +79,11 @@ pub fn run_nts_ke_client(
         }
     }
 
+    if let Some((certs, key)) = client_config.client_identity {
+        info!(logger, "using client certificate");
+        tls_config.set_single_client_cert(certs, key);
+    }
+
     let rc_config = Arc::new(tls_config);
     let hostname = webpki::DNSNameRef::try_from_ascii_str(client_config.host.as_str())
         .expect("server hostname is invalid");
@@ -126,15 +130,59 @@ pub fn run_nts_ke_client(
     tls_stream.write_all(clientrec)?;
     tls_stream.flush()?;
     debug!(logger, "Request transmitted");
-    let keys = records::gen_key(tls_stream.sess).unwrap();
 
+    // The handshake is done once the request is written. We don't trust a response, nor derive
+    // keys, from a server that didn't agree to NTS-KE over TLS 1.3.
+    records::check_tls_negotiation(tls_stream.sess)?;
+
+    let state = read_response(logger, &mut tls_stream)?;
+
+    // We only offered NTPv4 with AEAD_AES_SIV_CMAC_256, so the server has to choose both of them.
+    // Otherwise, there is nothing we can use the keys for.
+    let protocol = KnownNextProtocol::Ntpv4;
+    let algorithm = KnownAeadAlgorithm::AeadAesSivCmac256;
+    let next_protocols = state.next_protocols.unwrap_or_default();
+    if !next_protocols.contains(&protocol.as_protocol_id()) {
+        return Err(Box::new(NtsKeParseError::NoProtocolNegotiated));
+    }
+    let aead_scheme = match state.aead_scheme.as_deref() {
+        Some([algorithm_id]) if *algorithm_id == algorithm.as_algorithm_id() => *algorithm_id,
+        _ => return Err(Box::new(NtsKeParseError::NoAlgorithmNegotiated)),
+    };
+
+    let keys = records::gen_key(&client, protocol, algorithm)?;
+    stream.shutdown(Shutdown::Write)?;
+
+    Ok(NtsKeResult {
+        aead_scheme,
+        cookies: state.cookies,
+        next_protocols,
+        next_server: state.next_server.unwrap_or(client_config.host.clone()),
+        next_port: state.next_port.unwrap_or(DEFAULT_NTP_PORT),
+        keys,
+        use_ipv4: client_config.use_ipv4,
+    })
+}
+
+/// Read the response of the server up to the End of Message record.
+///
+/// # Errors
+///
+/// There will be an error if a record is malformed or not allowed, the server sent an Error
+/// record or a Warning record that we don't recognize, or we cannot read from `reader`.
+///
+fn read_response<R: Read>(
+    logger: &slog::Logger,
+    reader: &mut R,
+) -> Result<ReceivedNtsKeRecordState, Box<dyn Error>> {
     let mut state = ReceivedNtsKeRecordState {
         finished: false,
-        next_protocols: Vec::new(),
-        aead_scheme: Vec::new(),
+        next_protocols: None,
+        aead_scheme: None,
         cookies: Vec::new(),
         next_server: None,
         next_port: None,
+        warnings: Vec::new(),
     };
 
     while !state.finished {
@@ -142,7 +190,7 @@ pub fn run_nts_ke_client(
 
         // We should use `read_exact` here because we always need to read 4 bytes to get the
         // header.
-        if let Err(error) = tls_stream.read_exact(&mut header[..]) {
+        if let Err(error) = reader.read_exact(&mut header[..]) {
             return Err(Box::new(error));
         }
 
@@ -151,7 +199,7 @@ pub fn run_nts_ke_client(
         let mut body = vec![0; body_length as usize];
 
         // `read_exact` the length of the body.
-        if let Err(error) = tls_stream.read_exact(body.as_mut_slice()) {
+        if let Err(error) = reader.read_exact(body.as_mut_slice()) {
             return Err(Box::new(error));
         }
 
@@ -195,21 +243,48 @@ pub fn run_nts_ke_client(
         }
     }
     debug!(logger, "saw the end of the response");
-    stream.shutdown(Shutdown::Write)?;
 
-    let aead_scheme = if state.aead_scheme.is_empty() {
-        DEFAULT_SCHEME
-    } else {
-        state.aead_scheme[0]
-    };
+    // RFC 8915 section 4.1.4 requires us to treat a warning that we don't recognize as an error,
+    // and no warning code is defined yet.
+    for code in &state.warnings {
+        warn!(logger, "the NTS-KE server sent warning code {}", code);
+    }
+    if let Some(code) = state.warnings.first() {
+        return Err(Box::new(NtsKeParseError::UnknownWarning(*code)));
+    }
 
-    Ok(NtsKeResult {
-        aead_scheme,
-        cookies: state.cookies,
-        next_protocols: state.next_protocols,
-        next_server: state.next_server.unwrap_or(client_config.host.clone()),
-        next_port: state.next_port.unwrap_or(DEFAULT_NTP_PORT),
-        keys,
-        use_ipv4: client_config.use_ipv4,
-    })
+    Ok(state)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn response(warnings: &[u16]) -> Vec<u8> {
+        let mut bytes = serialize(NextProtocolRecord::from(vec![KnownNextProtocol::Ntpv4]));
+        for code in warnings {
+            // A critical Warning record with a two-byte body.
+            bytes.extend_from_slice(&[0x80, 3, 0, 2]);
+            bytes.extend_from_slice(&code.to_be_bytes());
+        }
+        bytes.append(&mut serialize(EndOfMessageRecord));
+        bytes
+    }
+
+    #[test]
+    fn test_read_response_warnings() {
+        let logger = slog::Logger::root(slog::Discard, slog::o!());
+
+        let bytes = response(&[]);
+        let state = read_response(&logger, &mut &bytes[..]).unwrap();
+        assert!(state.finished);
+
+        // A warning that we don't recognize aborts the exchange.
+        let bytes = response(&[0x8000, 1]);
+        let error = read_response(&logger, &mut &bytes[..]).unwrap_err();
+        match error.downcast_ref::<NtsKeParseError>() {
+            Some(NtsKeParseError::UnknownWarning(0x8000)) => {}
+            _ => panic!("unexpected error: {}", error),
+        }
+    }
 }
@@ -9,7 +9,7 @@ use std::convert::TryFrom;
 use super::KeRecordTrait;
 use super::Party;
 
-#[derive(Clone, Copy)]
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
 pub enum KnownAeadAlgorithm {
     AeadAesSivCmac256,
 }
@@ -20,19 +20,34 @@ impl KnownAeadAlgorithm {
             KnownAeadAlgorithm::AeadAesSivCmac256 => 15,
         }
     }
+
+    /// Return the algorithm with the given id, if we know it.
+    pub fn from_algorithm_id(algorithm_id: u16) -> Option<KnownAeadAlgorithm> {
+        match algorithm_id {
+            15 => Some(KnownAeadAlgorithm::AeadAesSivCmac256),
+            _ => None,
+        }
+    }
 }
 
-pub struct AeadAlgorithmRecord(Vec<KnownAeadAlgorithm>);
+// We keep the raw algorithm ids instead of `KnownAeadAlgorithm` because the peer is allowed to
+// send ids that we don't know. They are not errors. They just don't take part in the negotiation.
+pub struct AeadAlgorithmRecord(Vec<u16>);
 
 impl AeadAlgorithmRecord {
-    pub fn algorithms(&self) -> &[KnownAeadAlgorithm] {
+    pub fn algorithm_ids(&self) -> &[u16] {
         self.0.as_slice()
     }
 }
 
 impl From<Vec<KnownAeadAlgorithm>> for AeadAlgorithmRecord {
     fn from(algorithms: Vec<KnownAeadAlgorithm>) -> AeadAlgorithmRecord {
-        AeadAlgorithmRecord(algorithms)
+        AeadAlgorithmRecord(
+            algorithms
+                .iter()
+                .map(|algorithm| algorithm.as_algorithm_id())
+                .collect(),
+        )
     }
 }
 
@@ -57,10 +72,10 @@ impl KeRecordTrait for AeadAlgorithmRecord {
 
     fn into_bytes(self) -> Vec<u8> {
         let mut bytes = Vec::new();
-        for algorithm in self.0.iter() {
+        for algorithm_id in self.0.iter() {
             // The spec said that the protocol id must be in network byte order, so we have to
             // convert it to the big endian order here.
-            let algorithm_bytes = &algorithm.as_algorithm_id().to_be_bytes()[..];
+            let algorithm_bytes = &algorithm_id.to_be_bytes()[..];
 
             bytes.append(&mut Vec::from(algorithm_bytes))
         }
@@ -78,19 +93,11 @@ impl KeRecordTrait for AeadAlgorithmRecord {
             ));
         }
 
-        let mut algorithms = Vec::new();
-
-        for word in bytes.chunks_exact(2) {
-            let algorithm_code = u16::from_be_bytes([word[0], word[1]]);
-
-            let algorithm = KnownAeadAlgorithm::AeadAesSivCmac256;
-            if algorithm.as_algorithm_id() == algorithm_code {
-                algorithms.push(algorithm);
-            } else {
-                return Err(String::from("unknown AEAD algorithm id"));
-            }
-        }
+        let algorithm_ids = bytes
+            .chunks_exact(2)
+            .map(|word| u16::from_be_bytes([word[0], word[1]]))
+            .collect();
 
-        Ok(AeadAlgorithmRecord(algorithms))
+        Ok(AeadAlgorithmRecord(algorithm_ids))
     }
 }
@@ -4,25 +4,68 @@
 
 //! Error record representation.
 
+use std::fmt;
+
 use super::KeRecordTrait;
 use super::Party;
 
-enum ErrorKind {
+/// Error codes from RFC 8915 section 4.1.3.
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+pub enum ErrorKind {
+    /// The request contained a critical record that the server didn't recognize.
     UnrecognizedCriticalRecord,
+    /// The request was malformed.
     BadRequest,
+    /// The server couldn't process the request for an internal reason.
+    InternalServerError,
+    /// An error code that is not defined yet. According to the spec, the client must treat it
+    /// like any other error, so we have to keep it instead of failing the parse.
+    Unknown(u16),
 }
 
 impl ErrorKind {
-    fn as_code(&self) -> u16 {
+    pub fn as_code(&self) -> u16 {
         match self {
             ErrorKind::UnrecognizedCriticalRecord => 0,
             ErrorKind::BadRequest => 1,
+            ErrorKind::InternalServerError => 2,
+            ErrorKind::Unknown(code) => *code,
+        }
+    }
+
+    pub fn from_code(code: u16) -> ErrorKind {
+        match code {
+            0 => ErrorKind::UnrecognizedCriticalRecord,
+            1 => ErrorKind::BadRequest,
+            2 => ErrorKind::InternalServerError,
+            code => ErrorKind::Unknown(code),
+        }
+    }
+}
+
+impl fmt::Display for ErrorKind {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            ErrorKind::UnrecognizedCriticalRecord => write!(f, "Unrecognized Critical Record (0)"),
+            ErrorKind::BadRequest => write!(f, "Bad Request (1)"),
+            ErrorKind::InternalServerError => write!(f, "Internal Server Error (2)"),
+            ErrorKind::Unknown(code) => write!(f, "unknown error code ({})", code),
         }
     }
 }
 
 pub struct ErrorRecord(ErrorKind);
 
+impl ErrorRecord {
+    pub fn new(kind: ErrorKind) -> ErrorRecord {
+        ErrorRecord(kind)
+    }
+
+    pub fn kind(&self) -> ErrorKind {
+        self.0
+    }
+}
+
 impl KeRecordTrait for ErrorRecord {
     fn critical(&self) -> bool {
         true
@@ -48,16 +91,6 @@ impl KeRecordTrait for ErrorRecord {
 
         let error_code = u16::from_be_bytes([bytes[0], bytes[1]]);
 
-        let kind = ErrorKind::UnrecognizedCriticalRecord;
-        if kind.as_code() == error_code {
-            return Ok(ErrorRecord(kind));
-        }
-
-        let kind = ErrorKind::BadRequest;
-        if kind.as_code() == error_code {
-            return Ok(ErrorRecord(kind));
-        }
-
-        Err(String::from("unknown error code"))
+        Ok(ErrorRecord(ErrorKind::from_code(error_code)))
     }
 }
@@ -24,13 +24,17 @@ pub use self::port::*;
 pub use self::server::*;
 pub use self::warning::*;
 
-use rustls::TLSError;
 use std::fmt;
 
 use crate::cookie::NTSKeys;
+use crate::crypto::KeyExporter;
 
 pub const HEADER_SIZE: usize = 4;
 
+/// The ALPN protocol ID of NTS-KE.
+/// https://datatracker.ietf.org/doc/html/rfc8915#section-4
+pub const ALPN_PROTOCOL: &[u8] = b"ntske/1";
+
 pub enum KeRecord {
     EndOfMessage(EndOfMessageRecord),
     NextProtocol(NextProtocolRecord),
@@ -146,15 +150,28 @@ pub fn deserialize(sender: Party, bytes: &[u8]) -> Result<KeRecord, DeserializeE
     Ok(record)
 }
 
-/// gen_key computes the client and server keys using exporters.
-/// https://tools.ietf.org/html/draft-ietf-ntp-using-nts-for-ntp-28#section-4.3
-pub fn gen_key<T: rustls::Session>(session: &T) -> Result<NTSKeys, TLSError> {
+/// gen_key computes the client and server keys using exporters for the negotiated next protocol
+/// and AEAD algorithm.
+/// https://datatracker.ietf.org/doc/html/rfc8915#section-5.1
+pub fn gen_key<T: KeyExporter>(
+    session: &T,
+    protocol: KnownNextProtocol,
+    algorithm: KnownAeadAlgorithm,
+) -> Result<NTSKeys, T::Error> {
     let mut keys: NTSKeys = NTSKeys {
         c2s: [0; 32],
         s2c: [0; 32],
     };
-    let c2s_con = [0, 0, 0, 15, 0];
-    let s2c_con = [0, 0, 0, 15, 1];
+
+    // The context is the protocol id, followed by the algorithm id, followed by 0x00 for the
+    // client-to-server key or 0x01 for the server-to-client key.
+    let mut c2s_con = Vec::new();
+    c2s_con.extend(&protocol.as_protocol_id().to_be_bytes());
+    c2s_con.extend(&algorithm.as_algorithm_id().to_be_bytes());
+    let mut s2c_con = c2s_con.clone();
+    c2s_con.push(0);
+    s2c_con.push(1);
+
     let context_c2s = Some(&c2s_con[..]);
     let context_s2c = Some(&s2c_con[..]);
     let label = "EXPORTER-network-time-security".as_bytes();
@@ -164,6 +181,19 @@ pub fn gen_key<T: rustls::Session>(session: &T) -> Result<NTSKeys, TLSError> {
     Ok(keys)
 }
 
+/// Check that a finished TLS handshake negotiated NTS-KE over TLS 1.3, as RFC 8915 section 4
+/// requires. rustls finishes the handshake even if the peer doesn't offer or select our ALPN
+/// protocol, so both sides have to check it before using the session.
+pub fn check_tls_negotiation<S: rustls::Session>(session: &S) -> Result<(), NtsKeParseError> {
+    if session.get_alpn_protocol() != Some(ALPN_PROTOCOL) {
+        return Err(NtsKeParseError::NoAlpnNegotiated);
+    }
+    if session.get_protocol_version() != Some(rustls::ProtocolVersion::TLSv1_3) {
+        return Err(NtsKeParseError::NoTls13Negotiated);
+    }
+    Ok(())
+}
+
 // ------------------------------------------------------------------------
 // Record Process
 // ------------------------------------------------------------------------
@@ -173,17 +203,28 @@ type Cookie = Vec<u8>;
 #[derive(Clone, Debug)]
 pub struct ReceivedNtsKeRecordState {
     pub finished: bool,
-    pub next_protocols: Vec<u16>,
-    pub aead_scheme: Vec<u16>,
+    /// Protocol ids from the Next Protocol record. `None` if the record is not received yet.
+    pub next_protocols: Option<Vec<u16>>,
+    /// Algorithm ids from the AEAD Algorithm record. `None` if the record is not received yet.
+    pub aead_scheme: Option<Vec<u16>>,
     pub cookies: Vec<Cookie>,
     pub next_server: Option<String>,
     pub next_port: Option<u16>,
+    /// Warning codes in the order they are received.
+    pub warnings: Vec<u16>,
 }
 
 #[derive(Debug, Clone)]
 pub enum NtsKeParseError {
     RecordAfterEnd,
-    ErrorRecord,
+    DuplicateNextProtocol,
+    DuplicateAeadAlgorithm,
+    NoProtocolNegotiated,
+    NoAlgorithmNegotiated,
+    NoAlpnNegotiated,
+    NoTls13Negotiated,
+    ErrorRecord(ErrorKind),
+    UnknownWarning(u16),
     NoIpv4AddrFound,
     NoIpv6AddrFound,
 }
@@ -192,7 +233,14 @@ impl std::error::Error for NtsKeParseError {
     fn description(&self) -> &str {
         match self {
             Self::RecordAfterEnd => "Received record after connection finished",
-            Self::ErrorRecord => "Received NTS error record",
+            Self::DuplicateNextProtocol => "Received more than one Next Protocol record",
+            Self::DuplicateAeadAlgorithm => "Received more than one AEAD Algorithm record",
+            Self::NoProtocolNegotiated => "The server doesn't support NTPv4",
+            Self::NoAlgorithmNegotiated => "The server doesn't support any offered AEAD algorithm",
+            Self::NoAlpnNegotiated => "The peer didn't negotiate the ntske/1 ALPN protocol",
+            Self::NoTls13Negotiated => "The peer didn't negotiate TLS 1.3",
+            Self::ErrorRecord(_) => "Received NTS error record",
+            Self::UnknownWarning(_) => "Received NTS warning record with an unknown code",
             Self::NoIpv4AddrFound => {
                 "Connection to server failed: IPv4 address could not be resolved"
             }
@@ -208,7 +256,19 @@ impl std::error::Error for NtsKeParseError {
 
 impl fmt::Display for NtsKeParseError {
     fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
-        write!(f, "NTS-KE Record Parse Error")
+        match self {
+            Self::ErrorRecord(kind) => write!(f, "Received NTS-KE Error record: {}", kind),
+            Self::UnknownWarning(code) => {
+                write!(
+                    f,
+                    "Received NTS-KE Warning record with unknown code {}",
+                    code
+                )
+            }
+            Self::NoAlpnNegotiated => write!(f, "The ntske/1 ALPN protocol was not negotiated"),
+            Self::NoTls13Negotiated => write!(f, "TLS 1.3 was not negotiated"),
+            _ => write!(f, "NTS-KE Record Parse Error"),
+        }
     }
 }
 
@@ -224,20 +284,22 @@ pub fn process_record(
     match record {
         KeRecord::EndOfMessage(_) => state.finished = true,
         KeRecord::NextProtocol(record) => {
-            state.next_protocols = record
-                .protocols()
-                .iter()
-                .map(|protocol| protocol.as_protocol_id())
-                .collect();
+            // The spec allows exactly one Next Protocol record in a message.
+            if state.next_protocols.is_some() {
+                return Err(Box::new(NtsKeParseError::DuplicateNextProtocol));
+            }
+            state.next_protocols = Some(Vec::from(record.protocol_ids()));
+        }
+        KeRecord::Error(record) => {
+            return Err(Box::new(NtsKeParseError::ErrorRecord(record.kind())))
         }
-        KeRecord::Error(_) => return Err(Box::new(NtsKeParseError::ErrorRecord)),
-        KeRecord::Warning(_) => return Ok(()),
+        KeRecord::Warning(record) => state.warnings.push(record.code()),
         KeRecord::AeadAlgorithm(record) => {
-            state.aead_scheme = record
-                .algorithms()
-                .iter()
-                .map(|algorithm| algorithm.as_algorithm_id())
-                .collect();
+            // The spec allows at most one AEAD Algorithm record in a message.
+            if state.aead_scheme.is_some() {
+                return Err(Box::new(NtsKeParseError::DuplicateAeadAlgorithm));
+            }
+            state.aead_scheme = Some(Vec::from(record.algorithm_ids()));
         }
         KeRecord::NewCookie(record) => state.cookies.push(record.into_bytes()),
         KeRecord::Server(record) => state.next_server = Some(record.into_string()),
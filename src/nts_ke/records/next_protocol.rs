@@ -9,7 +9,7 @@ use std::convert::TryFrom;
 use super::KeRecordTrait;
 use super::Party;
 
-#[derive(Clone, Copy)]
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
 pub enum KnownNextProtocol {
     Ntpv4,
 }
@@ -20,19 +20,34 @@ impl KnownNextProtocol {
             KnownNextProtocol::Ntpv4 => 0,
         }
     }
+
+    /// Return the protocol with the given id, if we know it.
+    pub fn from_protocol_id(protocol_id: u16) -> Option<KnownNextProtocol> {
+        match protocol_id {
+            0 => Some(KnownNextProtocol::Ntpv4),
+            _ => None,
+        }
+    }
 }
 
-pub struct NextProtocolRecord(Vec<KnownNextProtocol>);
+// We keep the raw protocol ids instead of `KnownNextProtocol` because the peer is allowed to send
+// ids that we don't know. They are not errors. They just don't take part in the negotiation.
+pub struct NextProtocolRecord(Vec<u16>);
 
 impl NextProtocolRecord {
-    pub fn protocols(&self) -> &[KnownNextProtocol] {
+    pub fn protocol_ids(&self) -> &[u16] {
         self.0.as_slice()
     }
 }
 
 impl From<Vec<KnownNextProtocol>> for NextProtocolRecord {
     fn from(protocols: Vec<KnownNextProtocol>) -> NextProtocolRecord {
-        NextProtocolRecord(protocols)
+        NextProtocolRecord(
+            protocols
+                .iter()
+                .map(|protocol| protocol.as_protocol_id())
+                .collect(),
+        )
     }
 }
 
@@ -55,10 +70,10 @@ impl KeRecordTrait for NextProtocolRecord {
 
     fn into_bytes(self) -> Vec<u8> {
         let mut bytes = Vec::new();
-        for protocol in self.0.iter() {
+        for protocol_id in self.0.iter() {
             // The spec said that the protocol id must be in network byte order, so we have to
             // convert it to the big endian order here.
-            let protocol_bytes = &protocol.as_protocol_id().to_be_bytes()[..];
+            let protocol_bytes = &protocol_id.to_be_bytes()[..];
 
             bytes.append(&mut Vec::from(protocol_bytes))
         }
@@ -76,19 +91,11 @@ impl KeRecordTrait for NextProtocolRecord {
             ));
         }
 
-        let mut protocols = Vec::new();
-
-        for word in bytes.chunks_exact(2) {
-            let protocol_code = u16::from_be_bytes([word[0], word[1]]);
-
-            let protocol = KnownNextProtocol::Ntpv4;
-            if protocol.as_protocol_id() == protocol_code {
-                protocols.push(protocol);
-            } else {
-                return Err(String::from("unknown Next Protocol id"));
-            }
-        }
+        let protocol_ids = bytes
+            .chunks_exact(2)
+            .map(|word| u16::from_be_bytes([word[0], word[1]]))
+            .collect();
 
-        Ok(NextProtocolRecord(protocols))
+        Ok(NextProtocolRecord(protocol_ids))
     }
 }
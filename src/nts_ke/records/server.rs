@@ -3,8 +3,7 @@
 // See LICENSE for licensing information.
 
 //! Server negotiation record representation.
-/// This Server negotiation will not be sent from the server because currently, we are not
-/// interested in running an NTP server on different IP address.
+
 use std::convert::TryFrom;
 use std::net::Ipv4Addr;
 use std::net::Ipv6Addr;
@@ -19,12 +18,39 @@ enum Address {
     Ipv6Addr(Ipv6Addr),
 }
 
+impl From<String> for Address {
+    fn from(name: String) -> Address {
+        if let Ok(address) = Ipv4Addr::from_str(&name) {
+            Address::Ipv4Addr(address)
+        } else if let Ok(address) = Ipv6Addr::from_str(&name) {
+            Address::Ipv6Addr(address)
+        } else {
+            // If it's not a valid IPv4 or IPv6, it must be a hostname.
+            Address::Hostname(name)
+        }
+    }
+}
+
 pub struct ServerRecord {
     sender: Party,
     address: Address,
 }
 
 impl ServerRecord {
+    /// Create a Server record from a hostname or an IP address string.
+    ///
+    /// # Panics
+    ///
+    /// If the string is not ascii. Hostnames must be validated before calling this.
+    ///
+    pub fn new(sender: Party, server: String) -> ServerRecord {
+        assert!(server.is_ascii(), "the server name must be an ascii string");
+        ServerRecord {
+            sender,
+            address: Address::from(server),
+        }
+    }
+
     pub fn into_string(self) -> String {
         match self.address {
             Address::Hostname(name) => name,
@@ -73,16 +99,9 @@ impl KeRecordTrait for ServerRecord {
             return Err(String::from("the body is an invalid ascii string"));
         }
 
-        let address = if let Ok(address) = Ipv4Addr::from_str(&body) {
-            Address::Ipv4Addr(address)
-        } else if let Ok(address) = Ipv6Addr::from_str(&body) {
-            Address::Ipv6Addr(address)
-        } else {
-            // If the body is a valid ascii string, but not a valid IPv4 or IPv6, it must be a
-            // hostname.
-            Address::Hostname(body)
-        };
-
-        Ok(ServerRecord { sender, address })
+        Ok(ServerRecord {
+            sender,
+            address: Address::from(body),
+        })
     }
 }
@@ -3,28 +3,21 @@
 // See LICENSE for licensing information.
 
 //! Warning record representation.
+//!
+//! RFC 8915 doesn't define any warning code yet, so every code is one that we don't recognize.
+//! The code is still parsed, so that the client can report it before giving up.
 
 use super::KeRecordTrait;
 use super::Party;
 
-enum WarningKind {
-    // There is currently no warning specified in the spec, but we need to put something here to
-    // make the code compiles. Please remove this Dummy when there is a warning specified in the
-    // spec.
-    Dummy,
-}
+pub struct WarningRecord(u16);
 
-impl WarningKind {
-    fn as_code(&self) -> u16 {
-        match self {
-            // Put the max value for Dummy just to avoid colliding with the future warning code.
-            WarningKind::Dummy => u16::max_value(),
-        }
+impl WarningRecord {
+    pub fn code(&self) -> u16 {
+        self.0
     }
 }
 
-pub struct WarningRecord(WarningKind);
-
 impl KeRecordTrait for WarningRecord {
     fn critical(&self) -> bool {
         true
@@ -39,8 +32,8 @@ impl KeRecordTrait for WarningRecord {
     }
 
     fn into_bytes(self) -> Vec<u8> {
-        let error_code = &self.0.as_code().to_be_bytes()[..];
-        Vec::from(error_code)
+        let warning_code = &self.0.to_be_bytes()[..];
+        Vec::from(warning_code)
     }
 
     fn from_bytes(_: Party, bytes: &[u8]) -> Result<Self, String> {
@@ -50,11 +43,6 @@ impl KeRecordTrait for WarningRecord {
 
         let warning_code = u16::from_be_bytes([bytes[0], bytes[1]]);
 
-        let kind = WarningKind::Dummy;
-        if kind.as_code() == warning_code {
-            return Ok(WarningRecord(kind));
-        }
-
-        Err(String::from("unknown warning code"))
+        Ok(WarningRecord(warning_code))
     }
 }
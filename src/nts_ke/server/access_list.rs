@@ -0,0 +1,200 @@
+// This file is part of cfnts.
+// Copyright (c) 2019, Cloudflare. All rights reserved.
+// See LICENSE for licensing information.
+
+//! Network allow and deny lists of the listening addresses.
+//!
+//! The lists are reloaded from the configuration file on SIGHUP or when the file changes, without
+//! restarting the listeners.
+
+use lazy_static::lazy_static;
+
+use prometheus::{__register_counter_vec, opts, register_int_counter_vec, IntCounterVec};
+
+use slog::{error, info};
+
+use std::collections::HashMap;
+use std::fs;
+use std::net::{IpAddr, SocketAddr};
+use std::sync::{Arc, RwLock};
+use std::thread;
+use std::time::{Duration, SystemTime};
+
+use crate::cidr::Cidr;
+use crate::signal;
+
+use super::config::parse_access_lists;
+use super::ke_server::KeServerState;
+
+/// How often we check for SIGHUP and for changes of the configuration file.
+const CHECK_INTERVAL: Duration = Duration::from_secs(1);
+
+lazy_static! {
+    static ref RELOAD_COUNTER: IntCounterVec = register_int_counter_vec!(
+        "nts_ke_access_list_reload_total",
+        "Number of access list reloads by result",
+        &["result"]
+    )
+    .unwrap();
+}
+
+/// Networks that may or may not connect to a listening address.
+#[derive(Clone, Debug, Default, Eq, PartialEq)]
+pub struct AccessList {
+    /// If it's not empty, only clients in these networks are admitted.
+    pub allow: Vec<Cidr>,
+
+    /// Clients in these networks are refused.
+    pub deny: Vec<Cidr>,
+}
+
+impl AccessList {
+    /// Return true if a client with the address `ip` is admitted.
+    ///
+    /// The longest prefix that contains the address decides, so that a network can be carved out
+    /// of a larger one in the other list. If an address is in an allowed and a denied prefix of
+    /// the same length, it's refused.
+    pub fn admits(&self, ip: &IpAddr) -> bool {
+        let longest = |prefixes: &[Cidr]| {
+            prefixes
+                .iter()
+                .filter(|prefix| prefix.contains(ip))
+                .map(Cidr::prefix_len)
+                .max()
+        };
+        match (longest(&self.allow), longest(&self.deny)) {
+            (Some(allow), Some(deny)) => allow > deny,
+            (Some(_), None) => true,
+            (None, Some(_)) => false,
+            (None, None) => self.allow.is_empty(),
+        }
+    }
+}
+
+/// The access lists of all the listening addresses, shared among the listeners.
+pub(super) struct AccessLists {
+    lists: RwLock<HashMap<SocketAddr, AccessList>>,
+}
+
+impl AccessLists {
+    pub(super) fn new(lists: HashMap<SocketAddr, AccessList>) -> AccessLists {
+        AccessLists {
+            lists: RwLock::new(lists),
+        }
+    }
+
+    /// Return true if a client with the address `ip` may connect to `listen_addr`. A listening
+    /// address without an access list admits everyone.
+    pub(super) fn admits(&self, listen_addr: &SocketAddr, ip: &IpAddr) -> bool {
+        match self.lists.read().unwrap().get(listen_addr) {
+            Some(list) => list.admits(ip),
+            None => true,
+        }
+    }
+
+    /// Replace all the access lists. New connections are checked against the new ones.
+    fn replace(&self, lists: HashMap<SocketAddr, AccessList>) {
+        *self.lists.write().unwrap() = lists;
+    }
+}
+
+/// Return the modification time of a file, or `None` if it cannot be read.
+fn modification_time(filename: &str) -> Option<SystemTime> {
+    fs::metadata(filename)
+        .and_then(|metadata| metadata.modified())
+        .ok()
+}
+
+/// Create a new thread that reloads the access lists from the configuration file on SIGHUP or
+/// when the file changes. If the file cannot be parsed, the old lists are kept.
+///
+/// Nothing is reloaded if the configuration was not read from a file.
+pub(super) fn periodic_access_list_reload(state: Arc<KeServerState>) {
+    let config_file = match &state.config.config_file {
+        Some(config_file) => config_file.clone(),
+        None => return,
+    };
+    let logger = state
+        .config
+        .logger()
+        .new(slog::o!("component" => "access_list_reload"));
+
+    let mut last_sighup = signal::sighup_count();
+    let mut last_time = modification_time(&config_file);
+
+    thread::spawn(move || loop {
+        thread::sleep(CHECK_INTERVAL);
+
+        let sighup_count = signal::sighup_count();
+        let time = modification_time(&config_file);
+        if sighup_count == last_sighup && time == last_time {
+            continue;
+        }
+        let sighup = sighup_count != last_sighup;
+        last_sighup = sighup_count;
+        last_time = time;
+
+        info!(
+            logger,
+            "reloading access lists from {}", config_file;
+            "reason" => if sighup { "sighup" } else { "file change" }
+        );
+        match parse_access_lists(&config_file) {
+            Ok(lists) => {
+                info!(logger, "reloaded {} access lists", lists.len());
+                state.access_lists.replace(lists);
+                RELOAD_COUNTER.with_label_values(&["success"]).inc();
+            }
+            Err(err) => {
+                RELOAD_COUNTER.with_label_values(&["failure"]).inc();
+                error!(
+                    logger,
+                    "cannot reload access lists, keeping the old ones: {}", err
+                );
+            }
+        }
+    });
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn prefixes(prefixes: &[&str]) -> Vec<Cidr> {
+        prefixes
+            .iter()
+            .map(|prefix| prefix.parse().unwrap())
+            .collect()
+    }
+
+    #[test]
+    fn test_admits() {
+        let ip = |ip: &str| ip.parse::<IpAddr>().unwrap();
+
+        assert!(AccessList::default().admits(&ip("192.0.2.1")));
+
+        let list = AccessList {
+            allow: prefixes(&["10.0.0.0/8", "2001:db8::/32", "10.1.2.0/24"]),
+            deny: prefixes(&["10.1.0.0/16"]),
+        };
+        assert!(list.admits(&ip("10.2.0.1")));
+        assert!(list.admits(&ip("::ffff:10.2.0.1")));
+        assert!(list.admits(&ip("2001:db8::1")));
+        assert!(!list.admits(&ip("10.1.0.1")));
+        assert!(list.admits(&ip("10.1.2.1")));
+        assert!(!list.admits(&ip("192.0.2.1")));
+
+        let list = AccessList {
+            allow: prefixes(&["192.0.2.0/24"]),
+            deny: prefixes(&["192.0.2.0/24", "203.0.113.1"]),
+        };
+        assert!(!list.admits(&ip("192.0.2.1")));
+
+        let list = AccessList {
+            allow: Vec::new(),
+            deny: prefixes(&["203.0.113.1"]),
+        };
+        assert!(!list.admits(&ip("203.0.113.1")));
+        assert!(list.admits(&ip("203.0.113.2")));
+    }
+}
@@ -0,0 +1,199 @@
+// This file is part of cfnts.
+// Copyright (c) 2019, Cloudflare. All rights reserved.
+// See LICENSE for licensing information.
+
+//! NTS-KE session access log.
+//!
+//! Every session is written as one JSON object per line when its connection is closed.
+
+use rustls::Session;
+
+use serde_json::json;
+
+use std::fs::OpenOptions;
+use std::io::{self, Write};
+use std::net::SocketAddr;
+use std::sync::Mutex;
+use std::time::{SystemTime, UNIX_EPOCH};
+
+/// The access log destination that means the standard error instead of a file.
+const STDERR: &str = "stderr";
+
+/// Destination of the access log entries.
+pub(super) struct AccessLog {
+    output: Mutex<Box<dyn Write + Send>>,
+}
+
+impl AccessLog {
+    /// Open the access log. `destination` is either "stderr" or the name of a file, which the
+    /// entries are appended to.
+    ///
+    /// # Errors
+    ///
+    /// There will be an error if the file cannot be opened.
+    ///
+    pub(super) fn open(destination: &str) -> Result<AccessLog, io::Error> {
+        let output: Box<dyn Write + Send> = if destination == STDERR {
+            Box::new(io::stderr())
+        } else {
+            Box::new(
+                OpenOptions::new()
+                    .create(true)
+                    .append(true)
+                    .open(destination)?,
+            )
+        };
+        Ok(AccessLog {
+            output: Mutex::new(output),
+        })
+    }
+
+    /// Write an entry as one line.
+    ///
+    /// # Errors
+    ///
+    /// There will be an error if the line cannot be written.
+    ///
+    pub(super) fn write(&self, entry: &serde_json::Value) -> Result<(), io::Error> {
+        let mut line = entry.to_string();
+        line.push('\n');
+
+        // The line is written at once, so that the entries of different listeners don't
+        // interleave.
+        let mut output = self.output.lock().unwrap();
+        output.write_all(line.as_bytes())?;
+        output.flush()
+    }
+}
+
+/// What happened in an NTS-KE session so far.
+pub(super) struct SessionLog {
+    /// When the connection was accepted.
+    pub(super) start: SystemTime,
+
+    /// Address of the load balancer, if the client address comes from a PROXY protocol header.
+    pub(super) proxy_addr: Option<SocketAddr>,
+
+    /// The common name of the verified client certificate.
+    pub(super) client_identity: Option<String>,
+
+    /// Types of the received records in order, without the critical bit.
+    pub(super) records: Vec<u16>,
+
+    /// The negotiated next protocol id.
+    pub(super) next_protocol: Option<u16>,
+
+    /// The negotiated AEAD algorithm id.
+    pub(super) aead_algorithm: Option<u16>,
+
+    /// The number of cookies sent to the client.
+    pub(super) cookies: usize,
+
+    /// The NTP server and port sent to the client.
+    pub(super) next_server: Option<String>,
+    pub(super) next_port: Option<u16>,
+
+    /// The error code sent to the client.
+    pub(super) error_code: Option<u16>,
+
+    /// How the session ended. Only the first outcome is kept, because later ones are just
+    /// consequences of it.
+    pub(super) outcome: Option<&'static str>,
+}
+
+impl SessionLog {
+    pub(super) fn new() -> SessionLog {
+        SessionLog {
+            start: SystemTime::now(),
+            proxy_addr: None,
+            client_identity: None,
+            records: Vec::new(),
+            next_protocol: None,
+            aead_algorithm: None,
+            cookies: 0,
+            next_server: None,
+            next_port: None,
+            error_code: None,
+            outcome: None,
+        }
+    }
+
+    /// Record how the session ended, if it's not recorded yet.
+    pub(super) fn set_outcome(&mut self, outcome: &'static str) {
+        if self.outcome.is_none() {
+            self.outcome = Some(outcome);
+        }
+    }
+
+    /// Return the access log entry of the session.
+    pub(super) fn to_json(
+        &self,
+        listen_addr: &SocketAddr,
+        peer_addr: &SocketAddr,
+        tls_session: &rustls::ServerSession,
+    ) -> serde_json::Value {
+        let timestamp = self
+            .start
+            .duration_since(UNIX_EPOCH)
+            .unwrap_or_default()
+            .as_secs_f64();
+        let duration = self.start.elapsed().unwrap_or_default().as_secs_f64();
+
+        json!({
+            "timestamp": timestamp,
+            "duration": duration,
+            "listen_addr": listen_addr.to_string(),
+            "peer_addr": peer_addr.to_string(),
+            "proxy_addr": self.proxy_addr.map(|addr| addr.to_string()),
+            "sni": tls_session.get_sni_hostname(),
+            "alpn": tls_session
+                .get_alpn_protocol()
+                .map(|protocol| String::from_utf8_lossy(protocol).into_owned()),
+            "tls_version": tls_session
+                .get_protocol_version()
+                .map(|version| format!("{:?}", version)),
+            "client_identity": self.client_identity,
+            "cipher_suite": tls_session
+                .get_negotiated_ciphersuite()
+                .map(|suite| format!("{:?}", suite.suite)),
+            "records": self.records,
+            "next_protocol": self.next_protocol,
+            "aead_algorithm": self.aead_algorithm,
+            "cookies": self.cookies,
+            "next_server": self.next_server,
+            "next_port": self.next_port,
+            "error_code": self.error_code,
+            "outcome": self.outcome.unwrap_or("closed"),
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use std::sync::Arc;
+
+    #[test]
+    fn test_to_json() {
+        let tls_config = rustls::ServerConfig::new(rustls::NoClientAuth::new());
+        let tls_session = rustls::ServerSession::new(&Arc::new(tls_config));
+
+        let mut session = SessionLog::new();
+        session.records = vec![1, 4, 0];
+        session.error_code = Some(1);
+        session.set_outcome("error_sent");
+        session.set_outcome("eof");
+
+        let entry = session.to_json(
+            &"192.0.2.1:4460".parse().unwrap(),
+            &"198.51.100.1:56324".parse().unwrap(),
+            &tls_session,
+        );
+        assert_eq!(entry["peer_addr"], "198.51.100.1:56324");
+        assert_eq!(entry["records"], json!([1, 4, 0]));
+        assert_eq!(entry["error_code"], 1);
+        assert_eq!(entry["outcome"], "error_sent");
+        assert!(entry["sni"].is_null());
+    }
+}
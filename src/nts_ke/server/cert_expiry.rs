@@ -0,0 +1,211 @@
+// This file is part of cfnts.
+// Copyright (c) 2019, Cloudflare. All rights reserved.
+// See LICENSE for licensing information.
+
+//! Monitoring of the expiry of the TLS certificates.
+//!
+//! The expiry of each served leaf certificate is exported by SNI name, and the warnings get louder
+//! as it approaches. The metrics of a name go away once no certificate is served for it.
+
+use lazy_static::lazy_static;
+
+use prometheus::{__register_gauge_vec, opts, register_gauge_vec, GaugeVec};
+
+use slog::{error, warn};
+
+use std::collections::{HashMap, HashSet};
+use std::time::{SystemTime, UNIX_EPOCH};
+
+use crate::x509;
+
+use super::config::TlsIdentity;
+
+/// The label of the default certificate, which is served to clients without a known SNI name.
+const DEFAULT_NAME: &str = "default";
+
+/// Days before the expiry at which we warn again.
+const WARNING_DAYS: &[f64] = &[30.0, 14.0, 7.0, 3.0, 1.0];
+
+/// Days before the expiry from which the warnings are errors.
+const ERROR_DAYS: f64 = 3.0;
+
+lazy_static! {
+    static ref NOT_AFTER_GAUGE: GaugeVec = register_gauge_vec!(
+        "nts_ke_tls_cert_not_after_seconds",
+        "Unix time at which the served leaf TLS certificate expires, by SNI name",
+        &["name"]
+    )
+    .unwrap();
+    static ref DAYS_TO_EXPIRY_GAUGE: GaugeVec = register_gauge_vec!(
+        "nts_ke_tls_cert_days_to_expiry",
+        "Days until the served leaf TLS certificate expires, by SNI name. It's negative once the \
+         certificate is expired",
+        &["name"]
+    )
+    .unwrap();
+}
+
+/// Return the number of seconds from `now` to `time`, which is negative if `time` is in the past.
+fn seconds_from(now: SystemTime, time: SystemTime) -> f64 {
+    match time.duration_since(now) {
+        Ok(duration) => duration.as_secs_f64(),
+        Err(error) => -error.duration().as_secs_f64(),
+    }
+}
+
+/// Return the number of warning thresholds that a certificate with `days_left` has passed. An
+/// expired certificate has passed one more than all of them.
+fn passed_thresholds(days_left: f64) -> usize {
+    if days_left <= 0.0 {
+        return WARNING_DAYS.len() + 1;
+    }
+    WARNING_DAYS
+        .iter()
+        .filter(|&&days| days_left <= days)
+        .count()
+}
+
+/// Exporter of the certificate expiry metrics and logger of the expiry warnings.
+pub(super) struct CertExpiryMonitor {
+    /// The number of warning thresholds that the certificate of each file has passed, so that we
+    /// log only when it passes another one.
+    passed: HashMap<String, usize>,
+
+    /// The names whose metrics are exported.
+    names: HashSet<String>,
+}
+
+impl CertExpiryMonitor {
+    pub(super) fn new() -> CertExpiryMonitor {
+        CertExpiryMonitor {
+            passed: HashMap::new(),
+            names: HashSet::new(),
+        }
+    }
+
+    /// Update the metrics of the leaf certificates of `identities`, and log a warning when one of
+    /// them gets closer to its expiry. The first identity is the default one.
+    ///
+    /// The metrics of the names that are not in `identities` anymore are removed, so that a
+    /// certificate that was reloaded away doesn't trigger expiry alerts.
+    pub(super) fn check(&mut self, logger: &slog::Logger, identities: &[TlsIdentity]) {
+        let now = SystemTime::now();
+        let mut names_exported = HashSet::new();
+
+        for (index, identity) in identities.iter().enumerate() {
+            let not_after = match identity
+                .certs
+                .first()
+                .and_then(|cert| x509::not_after(&cert.0))
+            {
+                Some(not_after) => not_after,
+                // rustls would have rejected a certificate that we cannot parse at all.
+                None => continue,
+            };
+            let days_left = seconds_from(now, not_after) / 86_400.0;
+
+            let names = identity.names.iter().map(String::as_str);
+            let names: Vec<&str> = if index == 0 {
+                names.chain(Some(DEFAULT_NAME)).collect()
+            } else {
+                names.collect()
+            };
+            for name in names {
+                NOT_AFTER_GAUGE
+                    .with_label_values(&[name])
+                    .set(seconds_from(UNIX_EPOCH, not_after));
+                DAYS_TO_EXPIRY_GAUGE
+                    .with_label_values(&[name])
+                    .set(days_left);
+                names_exported.insert(String::from(name));
+            }
+
+            let passed = passed_thresholds(days_left);
+            let previous = self
+                .passed
+                .insert(identity.cert_file.clone(), passed)
+                .unwrap_or(0);
+            if passed <= previous {
+                continue;
+            }
+
+            if days_left <= 0.0 {
+                error!(
+                    logger,
+                    "the TLS certificate in {} is expired", identity.cert_file
+                );
+            } else if days_left <= ERROR_DAYS {
+                error!(
+                    logger,
+                    "the TLS certificate in {} expires in {:.1} days",
+                    identity.cert_file,
+                    days_left
+                );
+            } else {
+                warn!(
+                    logger,
+                    "the TLS certificate in {} expires in {:.0} days",
+                    identity.cert_file,
+                    days_left
+                );
+            }
+        }
+
+        for name in self.names.difference(&names_exported) {
+            // Every name in `self.names` has the gauges, so the removal doesn't fail.
+            let _ = NOT_AFTER_GAUGE.remove_label_values(&[name]);
+            let _ = DAYS_TO_EXPIRY_GAUGE.remove_label_values(&[name]);
+        }
+        self.names = names_exported;
+        self.passed.retain(|cert_file, _| {
+            identities
+                .iter()
+                .any(|identity| &identity.cert_file == cert_file)
+        });
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_passed_thresholds() {
+        assert_eq!(passed_thresholds(90.0), 0);
+        assert_eq!(passed_thresholds(30.0), 1);
+        assert_eq!(passed_thresholds(10.0), 2);
+        assert_eq!(passed_thresholds(0.5), 5);
+        assert_eq!(passed_thresholds(-0.5), 6);
+    }
+
+    /// Return the names that have the not-after metric.
+    fn exported_names() -> Vec<String> {
+        prometheus::gather()
+            .iter()
+            .filter(|family| family.get_name() == "nts_ke_tls_cert_not_after_seconds")
+            .flat_map(|family| family.get_metric().iter())
+            .flat_map(|metric| metric.get_label().iter())
+            .map(|label| String::from(label.get_value()))
+            .collect()
+    }
+
+    #[test]
+    fn test_check_removes_names() {
+        let logger = slog::Logger::root(slog::Discard, slog::o!());
+        let identity = |names: &[&str]| {
+            let names = names.iter().map(|name| String::from(*name)).collect();
+            let cert_file = String::from("tests/chain.pem");
+            TlsIdentity::load(names, cert_file, String::from("tests/tls-key.pem"), None).unwrap()
+        };
+
+        let mut monitor = CertExpiryMonitor::new();
+        monitor.check(&logger, &[identity(&[]), identity(&["stale.example"])]);
+        assert!(exported_names().contains(&String::from("stale.example")));
+        assert!(exported_names().contains(&String::from(DEFAULT_NAME)));
+
+        // The certificate for the name is not served after a reload.
+        monitor.check(&logger, &[identity(&[])]);
+        assert!(!exported_names().contains(&String::from("stale.example")));
+        assert!(exported_names().contains(&String::from(DEFAULT_NAME)));
+    }
+}
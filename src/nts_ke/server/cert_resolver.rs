@@ -0,0 +1,100 @@
+// This file is part of cfnts.
+// Copyright (c) 2019, Cloudflare. All rights reserved.
+// See LICENSE for licensing information.
+
+//! TLS certificate selection by SNI.
+
+use rustls::sign::{self, CertifiedKey};
+use rustls::{ResolvesServerCert, SignatureScheme, TLSError};
+
+use std::collections::HashMap;
+use std::sync::Arc;
+use std::time::SystemTime;
+
+use super::config::TlsIdentity;
+
+/// Make a `CertifiedKey` that rustls can sign with. The OCSP response is stapled, unless it's
+/// expired.
+///
+/// # Errors
+///
+/// There will be an error if the private key type is not supported by rustls.
+///
+fn certified_key(identity: &TlsIdentity) -> Result<CertifiedKey, TLSError> {
+    let signing_key = sign::any_supported_type(&identity.secret_key).map_err(|()| {
+        TLSError::General(format!(
+            "unsupported private key type in {}",
+            identity.key_file
+        ))
+    })?;
+    let mut key = CertifiedKey::new(identity.certs.clone(), Arc::new(signing_key));
+    key.ocsp = identity
+        .ocsp
+        .as_ref()
+        .filter(|response| !response.is_expired(SystemTime::now()))
+        .map(|response| response.der.clone());
+    Ok(key)
+}
+
+/// Certificate resolver that chooses the certificate chain by the server name that the client
+/// sends in SNI, and falls back to the default one when there is no SNI or the name is unknown.
+pub(super) struct SniCertResolver {
+    /// Certificate chains by lowercase server names.
+    by_name: HashMap<String, CertifiedKey>,
+
+    /// Certificate chain for clients without SNI or with an unknown server name.
+    default: CertifiedKey,
+}
+
+impl SniCertResolver {
+    /// Create a resolver from the identities. The first one is the default one.
+    ///
+    /// # Errors
+    ///
+    /// There will be an error if there is no identity, a private key type is not supported, or a
+    /// certificate is not valid for one of its names.
+    ///
+    pub(super) fn new(identities: &[TlsIdentity]) -> Result<SniCertResolver, TLSError> {
+        let default = match identities.first() {
+            Some(identity) => certified_key(identity)?,
+            None => {
+                return Err(TLSError::General(String::from(
+                    "no TLS certificate is configured",
+                )))
+            }
+        };
+
+        let mut by_name = HashMap::new();
+        for identity in identities {
+            let key = certified_key(identity)?;
+            for name in &identity.names {
+                let dns_name = webpki::DNSNameRef::try_from_ascii_str(name)
+                    .map_err(|_| TLSError::General(format!("{} is not a valid DNS name", name)))?;
+                key.cross_check_end_entity_cert(Some(dns_name))
+                    .map_err(|error| {
+                        TLSError::General(format!(
+                            "the certificate in {} is not valid for {}: {}",
+                            identity.cert_file, name, error
+                        ))
+                    })?;
+                by_name.insert(name.to_ascii_lowercase(), key.clone());
+            }
+        }
+
+        Ok(SniCertResolver { by_name, default })
+    }
+}
+
+impl ResolvesServerCert for SniCertResolver {
+    fn resolve(
+        &self,
+        server_name: Option<webpki::DNSNameRef>,
+        _sigschemes: &[SignatureScheme],
+    ) -> Option<CertifiedKey> {
+        let key = server_name.and_then(|name| {
+            let name: &str = name.into();
+            self.by_name.get(&name.to_ascii_lowercase())
+        });
+        Some(key.unwrap_or(&self.default).clone())
+    }
+}
@@ -5,18 +5,26 @@
 //! NTS-KE server configuration.
 
 use rustls::internal::pemfile;
-use rustls::{Certificate, PrivateKey};
+use rustls::{Certificate, PrivateKey, RootCertStore};
 
 use sloggers::terminal::TerminalLoggerBuilder;
 use sloggers::Build;
 
+use std::collections::{HashMap, HashSet};
 use std::convert::TryFrom;
 use std::fs::File;
-use std::net::SocketAddr;
+use std::net::{IpAddr, SocketAddr};
+use std::time::SystemTime;
 
+use crate::cidr::{canonical_ip, Cidr};
 use crate::cookie::CookieKey;
 use crate::error::WrapError;
 use crate::metrics::MetricsConfig;
+use crate::private_key;
+use crate::x509;
+
+use super::access_list::AccessList;
+use super::ocsp::OcspResponse;
 
 fn get_metrics_config(settings: &config::Config) -> Option<MetricsConfig> {
     let mut metrics = None;
@@ -31,6 +39,674 @@ fn get_metrics_config(settings: &config::Config) -> Option<MetricsConfig> {
     metrics
 }
 
+/// Get an optional NTP server name for the Server record from the settings.
+///
+/// # Errors
+///
+/// There will be an error if the value is not a string, or it cannot be sent in a Server record.
+///
+fn get_next_server(
+    settings: &config::Config,
+    key: &str,
+) -> Result<Option<String>, config::ConfigError> {
+    match settings.get_str(key) {
+        // If it's a not-found error, we can just leave it empty.
+        Err(config::ConfigError::NotFound(_)) => Ok(None),
+        Err(error) => Err(error),
+        Ok(server) => validate_next_server(server).map(Some),
+    }
+}
+
+/// Check that the NTP server name can be sent in a Server record.
+fn validate_next_server(server: String) -> Result<String, config::ConfigError> {
+    // The Server record carries an ascii hostname or IP address string.
+    if server.is_empty() || !server.is_ascii() || u16::try_from(server.len()).is_err() {
+        return Err(config::ConfigError::Message(format!(
+            "the next server {:?} is not a valid hostname or IP address",
+            server
+        )));
+    }
+    Ok(server)
+}
+
+/// Parse a port number from a config value.
+fn parse_port(value: config::Value, what: &str) -> Result<u16, config::ConfigError> {
+    match u16::try_from(value.into_int()?) {
+        Ok(port) => Ok(port),
+        Err(_) => Err(config::ConfigError::Message(format!(
+            "the {} is not a valid u16",
+            what
+        ))),
+    }
+}
+
+/// Parse one entry of `next_server_map`.
+///
+/// # Errors
+///
+/// There will be an error if the entry is not a table, the prefix is not a valid CIDR prefix,
+/// the next server is missing or invalid, or the next port is not a valid `u16`.
+///
+fn parse_next_server_mapping(
+    value: config::Value,
+) -> Result<NextServerMapping, config::ConfigError> {
+    let mut table = value.into_table()?;
+
+    let prefix = match table.remove("prefix") {
+        Some(prefix) => prefix
+            .into_str()?
+            .parse()
+            .map_err(config::ConfigError::Message)?,
+        None => return Err(config::ConfigError::NotFound(String::from("prefix"))),
+    };
+    let next_server = match table.remove("next_server") {
+        Some(server) => validate_next_server(server.into_str()?)?,
+        None => return Err(config::ConfigError::NotFound(String::from("next_server"))),
+    };
+    let next_port = match table.remove("next_port") {
+        Some(port) => Some(parse_port(port, "next port of a next server mapping")?),
+        None => None,
+    };
+
+    Ok(NextServerMapping {
+        prefix,
+        next_server,
+        next_port,
+    })
+}
+
+/// Parse one member of `next_server_pool`.
+///
+/// # Errors
+///
+/// There will be an error if the member is not a table, the server is missing or invalid, the
+/// port is not a valid `u16`, or the weight is not a positive `u32`.
+///
+fn parse_next_server_pool_member(
+    value: config::Value,
+    default_port: u16,
+) -> Result<NextServerPoolMember, config::ConfigError> {
+    let mut table = value.into_table()?;
+
+    let server = match table.remove("server") {
+        Some(server) => validate_next_server(server.into_str()?)?,
+        None => return Err(config::ConfigError::NotFound(String::from("server"))),
+    };
+    let port = match table.remove("port") {
+        Some(port) => parse_port(port, "port of a next server pool member")?,
+        None => default_port,
+    };
+    let weight = match table.remove("weight") {
+        Some(weight) => match u32::try_from(weight.into_int()?) {
+            Ok(weight) if weight > 0 => weight,
+            _ => {
+                return Err(config::ConfigError::Message(format!(
+                    "the weight of the next server pool member {} is not a positive u32",
+                    server
+                )))
+            }
+        },
+        None => 1,
+    };
+
+    Ok(NextServerPoolMember {
+        server,
+        port,
+        weight,
+    })
+}
+
+/// Get an optional positive integer from the settings, or the default value if it's not set.
+fn get_positive_int(
+    settings: &config::Config,
+    key: &str,
+    default: u64,
+) -> Result<u64, config::ConfigError> {
+    match settings.get_int(key) {
+        // If it's a not-found error, we just use the default value.
+        Err(config::ConfigError::NotFound(_)) => Ok(default),
+        Err(error) => Err(error),
+        Ok(val) => match u64::try_from(val) {
+            Ok(val) if val > 0 => Ok(val),
+            _ => Err(config::ConfigError::Message(format!(
+                "{} is not a positive integer",
+                key
+            ))),
+        },
+    }
+}
+
+/// Get an optional table from the settings.
+fn get_optional_table(
+    settings: &config::Config,
+    key: &str,
+) -> Result<Option<HashMap<String, config::Value>>, config::ConfigError> {
+    match settings.get_table(key) {
+        // If it's a not-found error, we can just leave it empty.
+        Err(config::ConfigError::NotFound(_)) => Ok(None),
+        Err(error) => Err(error),
+        Ok(table) => Ok(Some(table)),
+    }
+}
+
+/// Parse token bucket parameters from the table of the setting `key`.
+///
+/// # Errors
+///
+/// There will be an error if the rate or the burst is missing or is not a positive number.
+///
+fn parse_token_bucket(
+    table: &mut HashMap<String, config::Value>,
+    key: &str,
+) -> Result<TokenBucketConfig, config::ConfigError> {
+    let mut get_positive_float = |name: &str| -> Result<f64, config::ConfigError> {
+        match table.remove(name) {
+            Some(value) => match value.into_float()? {
+                value if value > 0.0 => Ok(value),
+                _ => Err(config::ConfigError::Message(format!(
+                    "the {} of {} is not a positive number",
+                    name, key
+                ))),
+            },
+            None => Err(config::ConfigError::NotFound(format!("{}.{}", key, name))),
+        }
+    };
+    let rate = get_positive_float("rate")?;
+    let burst = get_positive_float("burst")?;
+
+    Ok(TokenBucketConfig { rate, burst })
+}
+
+/// Parse an optional prefix length from a table, or return the default value if it's not set.
+fn parse_prefix_len(
+    table: &mut HashMap<String, config::Value>,
+    name: &str,
+    max_len: u8,
+    default: u8,
+) -> Result<u8, config::ConfigError> {
+    match table.remove(name) {
+        Some(value) => match u8::try_from(value.into_int()?) {
+            Ok(len) if len <= max_len => Ok(len),
+            _ => Err(config::ConfigError::Message(format!(
+                "{} is not a valid prefix length",
+                name
+            ))),
+        },
+        None => Ok(default),
+    }
+}
+
+/// Convert a number of worker threads to `usize`.
+fn parse_workers(workers: u64) -> Result<usize, config::ConfigError> {
+    usize::try_from(workers).map_err(|_| {
+        config::ConfigError::Message(String::from("the number of workers is too large"))
+    })
+}
+
+/// Check that a number of cookies is between one and the cap.
+fn validate_cookie_count(
+    count: u64,
+    max_cookie_count: usize,
+    what: &str,
+) -> Result<usize, config::ConfigError> {
+    match usize::try_from(count) {
+        Ok(count) if count > 0 && count <= max_cookie_count => Ok(count),
+        _ => Err(config::ConfigError::Message(format!(
+            "{} is not between 1 and max_cookie_count ({})",
+            what, max_cookie_count
+        ))),
+    }
+}
+
+/// Parse the optional `allow` and `deny` lists of network prefixes from the table of a listening
+/// address. Return `None` if both of them are empty.
+fn parse_access_list(
+    table: &mut HashMap<String, config::Value>,
+) -> Result<Option<AccessList>, config::ConfigError> {
+    let mut parse_prefixes = |name: &str| -> Result<Vec<Cidr>, config::ConfigError> {
+        match table.remove(name) {
+            Some(value) => value
+                .into_array()?
+                .into_iter()
+                .map(|value| {
+                    value
+                        .into_str()?
+                        .parse()
+                        .map_err(config::ConfigError::Message)
+                })
+                .collect(),
+            None => Ok(Vec::new()),
+        }
+    };
+    let allow = parse_prefixes("allow")?;
+    let deny = parse_prefixes("deny")?;
+
+    if allow.is_empty() && deny.is_empty() {
+        return Ok(None);
+    }
+    Ok(Some(AccessList { allow, deny }))
+}
+
+/// Read only the access lists of the listening addresses from a configuration file, so that they
+/// can be reloaded without the rest of the configuration.
+///
+/// # Errors
+///
+/// There will be an error if the file cannot be read, or a listening address or a prefix is
+/// invalid.
+///
+pub fn parse_access_lists(
+    filename: &str,
+) -> Result<HashMap<SocketAddr, AccessList>, config::ConfigError> {
+    let mut settings = config::Config::new();
+    settings.merge(config::File::with_name(filename))?;
+
+    let mut lists = HashMap::new();
+    for addr in settings.get_array("addr")? {
+        // Plain addresses have no listener-specific options.
+        if let Ok(mut table) = addr.into_table() {
+            let sock_addr: SocketAddr = match table.remove("addr") {
+                Some(value) => value.into_str()?.parse().wrap_err()?,
+                None => return Err(config::ConfigError::NotFound(String::from("addr"))),
+            };
+            if let Some(list) = parse_access_list(&mut table)? {
+                lists.insert(sock_addr, list);
+            }
+        }
+    }
+    Ok(lists)
+}
+
+/// Parameters of a token bucket.
+#[derive(Clone, Copy, Debug)]
+pub struct TokenBucketConfig {
+    /// Tokens added per second. Each connection takes one token.
+    pub rate: f64,
+
+    /// Maximum number of tokens, which is the largest burst of connections allowed.
+    pub burst: f64,
+}
+
+/// Limits of new connections from the same sources.
+#[derive(Clone, Debug)]
+pub struct RateLimitConfig {
+    /// Limit for each client address. `None` means unlimited.
+    pub per_ip: Option<TokenBucketConfig>,
+
+    /// Limit for each client network. `None` means unlimited.
+    pub per_prefix: Option<TokenBucketConfig>,
+
+    /// The length of the client networks for IPv4 clients.
+    pub ipv4_prefix_len: u8,
+
+    /// The length of the client networks for IPv6 clients.
+    pub ipv6_prefix_len: u8,
+}
+
+impl Default for RateLimitConfig {
+    fn default() -> RateLimitConfig {
+        RateLimitConfig {
+            per_ip: None,
+            per_prefix: None,
+            ipv4_prefix_len: 24,
+            ipv6_prefix_len: 48,
+        }
+    }
+}
+
+/// An NTP server that clients from a specific network are referred to.
+#[derive(Clone, Debug)]
+pub struct NextServerMapping {
+    /// The client network.
+    pub prefix: Cidr,
+
+    /// The NTP server to send in the Server record.
+    pub next_server: String,
+
+    /// The port to send in the Port record. If it's not set, the default next port is used.
+    pub next_port: Option<u16>,
+}
+
+/// A member of the pool of NTP servers that clients are spread across.
+#[derive(Clone, Debug)]
+pub struct NextServerPoolMember {
+    /// The NTP server to send in the Server record.
+    pub server: String,
+
+    /// The port to send in the Port record, and to probe.
+    pub port: u16,
+
+    /// The relative share of clients that are referred to this member.
+    pub weight: u32,
+}
+
+/// Configuration of the pool of NTP servers that clients are spread across.
+#[derive(Clone, Debug)]
+pub struct NextServerPoolConfig {
+    pub members: Vec<NextServerPoolMember>,
+
+    /// Seconds between two probes of the same member.
+    pub probe_interval: u64,
+
+    /// Seconds to wait for the response of a probe.
+    pub probe_timeout: u64,
+
+    /// The number of consecutive failed probes after which a member is considered unhealthy.
+    pub max_failures: u32,
+}
+
+/// Where the NTP server that a client is referred to comes from.
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+pub enum NextServerSource<'a> {
+    /// The client asked for the server and it's allowed.
+    Client,
+    /// The client network mapping with this prefix.
+    Mapping(&'a Cidr),
+    /// The listener-specific, address-family-specific, or default server.
+    Static,
+}
+
+/// The NTP server and port that a client is referred to in the NTS-KE response.
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+pub struct NextServer<'a> {
+    /// The NTP server to send in the Server record. If it's `None`, no Server record is sent.
+    pub server: Option<&'a str>,
+
+    /// The port to send in the Port record.
+    pub port: u16,
+
+    /// Where the server comes from.
+    pub source: NextServerSource<'a>,
+}
+
+/// Read TLS certificates from a PEM file.
+///
+/// # Errors
+///
+/// There will be an error if we cannot open the file or the content is not parsable to get
+/// certificates.
+///
+fn read_tls_certs(filename: &str) -> Result<Vec<Certificate>, std::io::Error> {
+    // Open a file. If there is any error, return it immediately.
+    let file = File::open(filename)?;
+
+    match pemfile::certs(&mut std::io::BufReader::new(file)) {
+        Ok(certs) if !certs.is_empty() => Ok(certs),
+        // We don't use Err(_) here because if the error type of `rustls` changes in the
+        // future, we will get noticed.
+        //
+        // The `std::io` module has an error kind of `InvalidData` which is perfectly
+        // suitable for our kind of error.
+        Ok(_) | Err(()) => Err(std::io::Error::new(
+            std::io::ErrorKind::InvalidData,
+            format!("cannot parse TLS certificates from {}", filename),
+        )),
+    }
+}
+
+/// Whether NTS-KE clients have to present a TLS certificate.
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+pub enum ClientAuthMode {
+    /// Client certificates are not requested.
+    Off,
+    /// Client certificates are requested and verified, but clients may connect without one.
+    Optional,
+    /// Clients without a valid certificate are refused.
+    Required,
+}
+
+/// TLS client authentication of the NTS-KE server.
+#[derive(Clone, Debug)]
+pub struct ClientAuthConfig {
+    pub mode: ClientAuthMode,
+
+    /// The PEM file of the CA certificates that client certificates must chain to. It's required
+    /// unless the mode is `Off`.
+    pub ca_file: Option<String>,
+}
+
+impl ClientAuthConfig {
+    /// Read the CA certificates for verifying client certificates.
+    ///
+    /// # Errors
+    ///
+    /// There will be an error if there is no CA file, we cannot open it, or it has no
+    /// certificates.
+    ///
+    pub fn load_roots(&self) -> Result<RootCertStore, std::io::Error> {
+        let filename = match &self.ca_file {
+            Some(filename) => filename,
+            None => {
+                return Err(std::io::Error::new(
+                    std::io::ErrorKind::InvalidInput,
+                    "no TLS client CA file",
+                ))
+            }
+        };
+
+        let mut roots = RootCertStore::empty();
+        for cert in read_tls_certs(filename)? {
+            roots.add(&cert).map_err(|error| {
+                std::io::Error::new(
+                    std::io::ErrorKind::InvalidData,
+                    format!("invalid CA certificate in {}: {:?}", filename, error),
+                )
+            })?;
+        }
+        Ok(roots)
+    }
+}
+
+/// Parse the `tls_client_auth` and `tls_client_ca_file` settings.
+///
+/// # Errors
+///
+/// There will be an error if the mode is unknown, or the CA file is missing or unreadable when
+/// client certificates are requested.
+///
+fn get_client_auth(settings: &config::Config) -> Result<ClientAuthConfig, config::ConfigError> {
+    let mode = match settings.get_str("tls_client_auth") {
+        // If it's a not-found error, client certificates are not requested.
+        Err(config::ConfigError::NotFound(_)) => ClientAuthMode::Off,
+        Err(error) => return Err(error),
+        Ok(mode) => match mode.as_str() {
+            "off" => ClientAuthMode::Off,
+            "optional" => ClientAuthMode::Optional,
+            "required" => ClientAuthMode::Required,
+            _ => {
+                return Err(config::ConfigError::Message(format!(
+                    "unknown TLS client authentication mode {}, expected off, optional, or \
+                     required",
+                    mode
+                )))
+            }
+        },
+    };
+    let ca_file = match settings.get_str("tls_client_ca_file") {
+        Err(config::ConfigError::NotFound(_)) => None,
+        Err(error) => return Err(error),
+        Ok(ca_file) => Some(ca_file),
+    };
+
+    let client_auth = ClientAuthConfig { mode, ca_file };
+    if mode != ClientAuthMode::Off {
+        // Fail fast, instead of when the TLS configuration is built.
+        client_auth.load_roots().wrap_err()?;
+    }
+    Ok(client_auth)
+}
+
+/// The default lifetime of TLS session tickets in seconds.
+const DEFAULT_TLS_SESSION_TICKET_LIFETIME: u32 = 7200;
+
+/// The maximum lifetime of TLS 1.3 session tickets in seconds, from RFC 8446 section 4.6.1.
+const MAX_TLS_SESSION_TICKET_LIFETIME: u64 = 604_800;
+
+/// Parse the `tls_session_tickets` and `tls_session_ticket_lifetime` settings.
+///
+/// # Errors
+///
+/// There will be an error if tickets are enabled and the lifetime is not a positive integer or
+/// is longer than TLS 1.3 allows.
+///
+fn get_session_ticket_lifetime(
+    settings: &config::Config,
+) -> Result<Option<u32>, config::ConfigError> {
+    let enabled = match settings.get_bool("tls_session_tickets") {
+        // If it's a not-found error, tickets are issued.
+        Err(config::ConfigError::NotFound(_)) => true,
+        Err(error) => return Err(error),
+        Ok(enabled) => enabled,
+    };
+    if !enabled {
+        return Ok(None);
+    }
+
+    let lifetime = get_positive_int(
+        settings,
+        "tls_session_ticket_lifetime",
+        u64::from(DEFAULT_TLS_SESSION_TICKET_LIFETIME),
+    )?;
+    if lifetime > MAX_TLS_SESSION_TICKET_LIFETIME {
+        return Err(config::ConfigError::Message(format!(
+            "tls_session_ticket_lifetime is longer than {} seconds",
+            MAX_TLS_SESSION_TICKET_LIFETIME
+        )));
+    }
+    // It cannot fail, because the lifetime is at most MAX_TLS_SESSION_TICKET_LIFETIME.
+    Ok(Some(lifetime as u32))
+}
+
+/// Parse one entry of `tls_sni_certs`.
+///
+/// # Errors
+///
+/// There will be an error if the entry is not a table, a field is missing, there is no name, or
+/// the files cannot be read.
+///
+fn parse_tls_sni_identity(value: config::Value) -> Result<TlsIdentity, config::ConfigError> {
+    let mut table = value.into_table()?;
+
+    let mut names = Vec::new();
+    match table.remove("names") {
+        Some(values) => {
+            for name in values.into_array()? {
+                names.push(name.into_str()?.to_ascii_lowercase());
+            }
+        }
+        None => return Err(config::ConfigError::NotFound(String::from("names"))),
+    }
+    if names.is_empty() {
+        return Err(config::ConfigError::Message(String::from(
+            "a TLS SNI certificate must have at least one name",
+        )));
+    }
+
+    let cert_file = match table.remove("cert_file") {
+        Some(cert_file) => cert_file.into_str()?,
+        None => return Err(config::ConfigError::NotFound(String::from("cert_file"))),
+    };
+    let key_file = match table.remove("key_file") {
+        Some(key_file) => key_file.into_str()?,
+        None => return Err(config::ConfigError::NotFound(String::from("key_file"))),
+    };
+    let ocsp_file = match table.remove("ocsp_file") {
+        Some(ocsp_file) => Some(ocsp_file.into_str()?),
+        None => None,
+    };
+
+    TlsIdentity::load(names, cert_file, key_file, ocsp_file).wrap_err()
+}
+
+/// Check that none of the leaf certificates is expired.
+///
+/// # Errors
+///
+/// There will be an error naming the file of the first expired certificate.
+///
+fn check_cert_expiry(identities: &[TlsIdentity]) -> Result<(), config::ConfigError> {
+    let now = SystemTime::now();
+    for identity in identities {
+        if let Some(not_after) = x509::not_after(&identity.certs[0].0) {
+            if not_after <= now {
+                return Err(config::ConfigError::Message(format!(
+                    "the TLS certificate in {} is expired",
+                    identity.cert_file
+                )));
+            }
+        }
+    }
+    Ok(())
+}
+
+/// A TLS certificate chain with its private key, and the SNI names for which it's served.
+#[derive(Clone, Debug)]
+pub struct TlsIdentity {
+    /// Server names for which this identity is chosen. The default identity doesn't need any.
+    pub names: Vec<String>,
+
+    /// The PEM file of the certificate chain.
+    pub cert_file: String,
+
+    /// The PEM file of the private key.
+    pub key_file: String,
+
+    /// The DER file of the OCSP response for the certificate, which an external tool refreshes.
+    pub ocsp_file: Option<String>,
+
+    pub certs: Vec<Certificate>,
+    pub secret_key: PrivateKey,
+
+    /// The OCSP response to staple, if there is a file. It's not stapled anymore once it
+    /// expires.
+    pub ocsp: Option<OcspResponse>,
+}
+
+impl TlsIdentity {
+    /// Read the certificate chain, the private key, and the OCSP response from the files. The
+    /// private key may be a PKCS#1, SEC1 or PKCS#8 key, in PEM or DER.
+    ///
+    /// # Errors
+    ///
+    /// There will be an error if we cannot open one of the files, the content is not parsable,
+    /// the private key doesn't match the first certificate of the chain, or the OCSP response
+    /// doesn't say that the certificate is good. An expired OCSP response is not an error.
+    ///
+    // All filenames must be given with relative paths to where the server is run. Otherwise,
+    // cfnts will try to open the file while in the incorrect directory.
+    pub fn load(
+        names: Vec<String>,
+        cert_file: String,
+        key_file: String,
+        ocsp_file: Option<String>,
+    ) -> Result<TlsIdentity, std::io::Error> {
+        let certs = read_tls_certs(&cert_file)?;
+        let (secret_key, key_type) = private_key::read_private_key(&key_file)?;
+        private_key::check_key_matches(&certs[0], &secret_key).map_err(|reason| {
+            std::io::Error::new(
+                std::io::ErrorKind::InvalidData,
+                format!(
+                    "the {} private key in {} doesn't match the certificate in {}: {}",
+                    key_type, key_file, cert_file, reason
+                ),
+            )
+        })?;
+        let ocsp = match &ocsp_file {
+            Some(ocsp_file) => Some(OcspResponse::load(ocsp_file, &certs[0].0)?),
+            None => None,
+        };
+        Ok(TlsIdentity {
+            names,
+            cert_file,
+            key_file,
+            ocsp_file,
+            certs,
+            secret_key,
+            ocsp,
+        })
+    }
+}
+
 /// Configuration for running an NTS-KE server.
 #[derive(Debug)]
 pub struct KeServerConfig {
@@ -41,8 +717,12 @@ pub struct KeServerConfig {
     /// The initial cookie key for the NTS-KE server.
     cookie_key: CookieKey,
 
+    /// Seconds for the TLS handshake, counted from when the connection is accepted.
     // If you don't to have a timeout, just set it to a very high value.
-    timeout: u64,
+    handshake_timeout: u64,
+
+    /// Seconds for the request and the response, counted from when the TLS handshake is done.
+    request_timeout: u64,
 
     /// The logger that will be used throughout the application, while the server is running.
     /// This property is mandatory because logging is very important for debugging.
@@ -52,10 +732,100 @@ pub struct KeServerConfig {
     /// NTS-KE server and the NTP server.
     memcached_url: String,
 
+    /// NTP servers that clients may ask for with their own Server record. A client's request is
+    /// ignored, if it's not in this list.
+    pub allowed_client_next_servers: Vec<String>,
+
+    /// NTP servers to send in the Server record for specific listening addresses.
+    listener_next_servers: HashMap<SocketAddr, String>,
+
+    /// Numbers of worker threads for specific listening addresses.
+    listener_workers: HashMap<SocketAddr, usize>,
+
+    /// Numbers of cookies to send to the clients of specific listening addresses.
+    listener_cookie_counts: HashMap<SocketAddr, usize>,
+
+    /// Listening addresses behind a load balancer that sends a PROXY protocol header before the
+    /// TLS handshake.
+    listener_proxy_protocol: HashSet<SocketAddr>,
+
+    /// PROXY protocol listening addresses that also admit clients connecting directly, from
+    /// outside `proxy_protocol_trusted_sources`.
+    listener_proxy_direct_clients: HashSet<SocketAddr>,
+
+    /// Networks that may or may not connect to specific listening addresses. They can be reloaded
+    /// from `config_file` while the server is running.
+    listener_access_lists: HashMap<SocketAddr, AccessList>,
+
+    /// Names of the sockets passed with systemd socket activation for specific listening
+    /// addresses. Other addresses are matched to the passed sockets by the address.
+    listener_socket_names: HashMap<SocketAddr, String>,
+
+    /// NTP servers and ports to send to clients from specific networks. When several prefixes
+    /// contain the client address, the longest one wins.
+    next_server_mappings: Vec<NextServerMapping>,
+
     pub metrics_config: Option<MetricsConfig>,
     pub next_port: u16,
-    pub tls_certs: Vec<Certificate>,
-    pub tls_secret_keys: Vec<PrivateKey>,
+
+    /// The number of cookies to send to a client with the keys. RFC 8915 recommends eight for
+    /// NTPv4.
+    pub cookie_count: usize,
+
+    /// The upper limit of `cookie_count` and its listener-specific overrides.
+    pub max_cookie_count: usize,
+
+    /// The pool of NTP servers that clients are spread across, when no client network mapping
+    /// applies. If it's not set, or no member is healthy, the static next server is used.
+    pub next_server_pool: Option<NextServerPoolConfig>,
+
+    /// The NTP server to send in the Server record. If it's not set, no Server record is sent and
+    /// clients will use the address of the NTS-KE server.
+    pub next_server: Option<String>,
+
+    /// Override of `next_server` for clients connecting over IPv4.
+    pub next_server_ipv4: Option<String>,
+
+    /// Override of `next_server` for clients connecting over IPv6.
+    pub next_server_ipv6: Option<String>,
+
+    /// Limits of new connections from the same client addresses and networks.
+    pub rate_limit: RateLimitConfig,
+
+    /// The maximum number of concurrent connections of each listening address, shared by all its
+    /// workers. `None` means unlimited.
+    pub max_connections: Option<usize>,
+
+    /// The number of worker threads for each listening address. If it's more than one, each
+    /// worker has its own socket bound with `SO_REUSEPORT`, and the kernel spreads the
+    /// connections across them.
+    pub workers: usize,
+
+    /// Seconds to let in-flight connections finish after a shutdown is requested.
+    pub shutdown_timeout: u64,
+
+    /// Where to write one JSON line per NTS-KE session: "stderr" or a file name. `None` means no
+    /// access log.
+    pub access_log: Option<String>,
+
+    /// Networks of the load balancers whose PROXY protocol headers are trusted. On PROXY protocol
+    /// listeners, connections from other sources are refused, unless the listener admits direct
+    /// clients with `proxy_protocol_direct_clients`.
+    pub proxy_protocol_trusted_sources: Vec<Cidr>,
+
+    /// Certificate chains and private keys of the server. The first one is the default one, which
+    /// is served to clients that don't send SNI or send a name that no other identity has.
+    pub tls_identities: Vec<TlsIdentity>,
+
+    /// Whether and how clients are authenticated with TLS certificates.
+    pub tls_client_auth: ClientAuthConfig,
+
+    /// Seconds that clients may resume TLS sessions with a session ticket. `None` means that no
+    /// tickets are issued.
+    pub tls_session_ticket_lifetime: Option<u32>,
+
+    /// The file that the configuration was parsed from, if any.
+    pub config_file: Option<String>,
 }
 
 /// We decided to make KeServerConfig mutable so that you can add more cert, private key, or
@@ -63,6 +833,8 @@ pub struct KeServerConfig {
 impl KeServerConfig {
     /// Create a NTS-KE server config object with the given next port, memcached url, connection
     /// timeout, and the metrics config.
+    ///
+    /// The connection timeout is used for both the TLS handshake and the request.
     pub fn new(
         timeout: u64,
         cookie_key: CookieKey,
@@ -82,30 +854,51 @@ impl KeServerConfig {
                 .build()
                 .expect("BUG: TerminalLoggerBuilder::build shouldn't return an error."),
 
-            tls_certs: Vec::new(),
-            tls_secret_keys: Vec::new(),
+            tls_identities: Vec::new(),
+            tls_client_auth: ClientAuthConfig {
+                mode: ClientAuthMode::Off,
+                ca_file: None,
+            },
+            tls_session_ticket_lifetime: Some(DEFAULT_TLS_SESSION_TICKET_LIFETIME),
+
+            rate_limit: RateLimitConfig::default(),
+            max_connections: None,
+            workers: 1,
+            listener_workers: HashMap::new(),
+            cookie_count: 8,
+            max_cookie_count: 16,
+            listener_cookie_counts: HashMap::new(),
+            shutdown_timeout: 10,
+            access_log: None,
+            listener_proxy_protocol: HashSet::new(),
+            listener_proxy_direct_clients: HashSet::new(),
+            listener_access_lists: HashMap::new(),
+            listener_socket_names: HashMap::new(),
+            config_file: None,
+            proxy_protocol_trusted_sources: Vec::new(),
+
+            next_server: None,
+            next_server_ipv4: None,
+            next_server_ipv6: None,
+            listener_next_servers: HashMap::new(),
+            next_server_mappings: Vec::new(),
+            next_server_pool: None,
+            allowed_client_next_servers: Vec::new(),
 
             // From parameters.
             cookie_key,
-            timeout,
+            handshake_timeout: timeout,
+            request_timeout: timeout,
             memcached_url,
             metrics_config,
             next_port,
         }
     }
 
-    /// Add a TLS certificate into the config.
-    // Because the order of `tls_certs` has to correspond to the order of `tls_secret_keys`, this
-    // method has to be private for now.
-    fn add_tls_cert(&mut self, cert: Certificate) {
-        self.tls_certs.push(cert);
-    }
-
-    /// Add a TLS private key into the config.
-    // Because the order of `tls_certs` has to correspond to the order of `tls_secret_keys`, this
-    // method has to be private for now.
-    fn add_tls_secret_key(&mut self, secret_key: PrivateKey) {
-        self.tls_secret_keys.push(secret_key);
+    /// Add a TLS certificate chain and its private key into the config. The first one added is
+    /// the default one.
+    pub fn add_tls_identity(&mut self, identity: TlsIdentity) {
+        self.tls_identities.push(identity);
     }
 
     /// Add an address into the config.
@@ -118,6 +911,156 @@ impl KeServerConfig {
         self.addrs.as_slice()
     }
 
+    /// Set the NTP server to send in the Server record for clients of a listening address.
+    pub fn set_listener_next_server(&mut self, addr: SocketAddr, server: String) {
+        self.listener_next_servers.insert(addr, server);
+    }
+
+    /// Set the number of worker threads for a listening address.
+    pub fn set_listener_workers(&mut self, addr: SocketAddr, workers: usize) {
+        self.listener_workers.insert(addr, workers);
+    }
+
+    /// Return the number of worker threads for a listening address.
+    pub fn workers(&self, addr: &SocketAddr) -> usize {
+        *self.listener_workers.get(addr).unwrap_or(&self.workers)
+    }
+
+    /// Set the number of cookies to send to the clients of a listening address.
+    pub fn set_listener_cookie_count(&mut self, addr: SocketAddr, count: usize) {
+        self.listener_cookie_counts.insert(addr, count);
+    }
+
+    /// Return the number of cookies to send to the clients of a listening address.
+    pub fn cookie_count(&self, addr: &SocketAddr) -> usize {
+        *self
+            .listener_cookie_counts
+            .get(addr)
+            .unwrap_or(&self.cookie_count)
+    }
+
+    /// Expect a PROXY protocol header on the connections to a listening address.
+    pub fn enable_listener_proxy_protocol(&mut self, addr: SocketAddr) {
+        self.listener_proxy_protocol.insert(addr);
+    }
+
+    /// Admit clients that connect directly, without a PROXY protocol header, to a PROXY protocol
+    /// listening address. Otherwise, only the trusted load balancers may connect to it.
+    pub fn allow_listener_proxy_direct_clients(&mut self, addr: SocketAddr) {
+        self.listener_proxy_direct_clients.insert(addr);
+    }
+
+    /// Return true if the connections from `peer_addr` to the listening address `listen_addr`
+    /// are refused, because it's a PROXY protocol listener and `peer_addr` is not a trusted load
+    /// balancer.
+    pub fn refuses_untrusted_source(
+        &self,
+        listen_addr: &SocketAddr,
+        peer_addr: &SocketAddr,
+    ) -> bool {
+        self.listener_proxy_protocol.contains(listen_addr)
+            && !self.listener_proxy_direct_clients.contains(listen_addr)
+            && !self.expects_proxy_header(listen_addr, peer_addr)
+    }
+
+    /// Return true if a PROXY protocol header is expected from `peer_addr` on the listening
+    /// address `listen_addr`.
+    pub fn expects_proxy_header(&self, listen_addr: &SocketAddr, peer_addr: &SocketAddr) -> bool {
+        let peer_ip = canonical_ip(peer_addr.ip());
+        self.listener_proxy_protocol.contains(listen_addr)
+            && self
+                .proxy_protocol_trusted_sources
+                .iter()
+                .any(|prefix| prefix.contains(&peer_ip))
+    }
+
+    /// Set the networks that may or may not connect to a listening address.
+    pub fn set_listener_access_list(&mut self, addr: SocketAddr, list: AccessList) {
+        self.listener_access_lists.insert(addr, list);
+    }
+
+    /// Return the access lists of the listening addresses that have one.
+    pub fn access_lists(&self) -> &HashMap<SocketAddr, AccessList> {
+        &self.listener_access_lists
+    }
+
+    /// Set the name of the socket passed with systemd socket activation for a listening address.
+    pub fn set_listener_socket_name(&mut self, addr: SocketAddr, name: String) {
+        self.listener_socket_names.insert(addr, name);
+    }
+
+    /// Return the name of the socket passed with systemd socket activation for a listening
+    /// address, if it's set.
+    pub fn socket_name(&self, addr: &SocketAddr) -> Option<&str> {
+        self.listener_socket_names.get(addr).map(String::as_str)
+    }
+
+    /// Add a mapping from a client network to the NTP server and port that its clients are
+    /// referred to.
+    pub fn add_next_server_mapping(&mut self, mapping: NextServerMapping) {
+        self.next_server_mappings.push(mapping);
+    }
+
+    /// Return the NTP server and port that should be sent in the Server and Port records to a
+    /// client connected from `peer_addr` to the listening address `listen_addr`.
+    ///
+    /// `requested` is the server that the client asked for, if any. It's honoured only when it's
+    /// allowed in the config. Otherwise, the mapping with the longest prefix containing the client
+    /// address is preferred, then the listener-specific server, then the address-family-specific
+    /// one, and then the default one.
+    pub fn next_server(
+        &self,
+        listen_addr: &SocketAddr,
+        peer_addr: &SocketAddr,
+        requested: Option<&str>,
+    ) -> NextServer<'_> {
+        if let Some(requested) = requested {
+            if let Some(server) = self
+                .allowed_client_next_servers
+                .iter()
+                .find(|server| server.eq_ignore_ascii_case(requested))
+            {
+                return NextServer {
+                    server: Some(server),
+                    port: self.next_port,
+                    source: NextServerSource::Client,
+                };
+            }
+        }
+
+        let peer_ip = canonical_ip(peer_addr.ip());
+
+        if let Some(mapping) = self
+            .next_server_mappings
+            .iter()
+            .filter(|mapping| mapping.prefix.contains(&peer_ip))
+            .max_by_key(|mapping| mapping.prefix.prefix_len())
+        {
+            return NextServer {
+                server: Some(&mapping.next_server),
+                port: mapping.next_port.unwrap_or(self.next_port),
+                source: NextServerSource::Mapping(&mapping.prefix),
+            };
+        }
+
+        let server = match self.listener_next_servers.get(listen_addr) {
+            Some(server) => Some(server),
+            None => {
+                let family_server = match peer_ip {
+                    IpAddr::V4(_) => &self.next_server_ipv4,
+                    IpAddr::V6(_) => &self.next_server_ipv6,
+                };
+                family_server.as_ref().or(self.next_server.as_ref())
+            }
+        };
+
+        NextServer {
+            server: server.map(String::as_str),
+            port: self.next_port,
+            source: NextServerSource::Static,
+        }
+    }
+
     /// Return the cookie key of the config.
     pub fn cookie_key(&self) -> &CookieKey {
         &self.cookie_key
@@ -138,77 +1081,14 @@ impl KeServerConfig {
         &self.memcached_url
     }
 
-    /// Return the connection timeout of the config.
-    pub fn timeout(&self) -> u64 {
-        self.timeout
+    /// Return the TLS handshake timeout of the config.
+    pub fn handshake_timeout(&self) -> u64 {
+        self.handshake_timeout
     }
 
-    /// Import TLS certificates from a file.
-    ///
-    /// # Errors
-    ///
-    /// There will be an error if we cannot open the file or the content is not parsable to get
-    /// certificates.
-    ///
-    // Because the order of `tls_certs` has to correspond to the order of `tls_secret_keys`, this
-    // method has to be private for now.
-    fn import_tls_certs(&mut self, filename: &str) -> Result<(), std::io::Error> {
-        // Open a file. If there is any error, return it immediately.
-        let file = File::open(filename)?;
-
-        match pemfile::certs(&mut std::io::BufReader::new(file)) {
-            Ok(certs) => {
-                // Add all parsed certificates.
-                for cert in certs {
-                    self.add_tls_cert(cert);
-                }
-                // Return success.
-                Ok(())
-            }
-            // We don't use Err(_) here because if the error type of `rustls` changes in the
-            // future, we will get noticed.
-            //
-            // The `std::io` module has an error kind of `InvalidData` which is perfectly
-            // suitable for our kind of error.
-            Err(()) => Err(std::io::Error::new(
-                std::io::ErrorKind::InvalidData,
-                format!("cannot parse TLS certificates from {}", filename),
-            )),
-        }
-    }
-
-    /// Import TLS private keys from a file.
-    ///
-    /// # Errors
-    ///
-    /// There will be an error if we cannot open the file or the content is not parsable to get
-    /// private keys.
-    ///
-    // Because the order of `tls_certs` has to correspond to the order of `tls_secret_keys`, this
-    // method has to be private for now.
-    fn import_tls_secret_keys(&mut self, filename: &str) -> Result<(), std::io::Error> {
-        // Open a file. If there is any error, return it immediately.
-        let file = File::open(filename)?;
-
-        match pemfile::pkcs8_private_keys(&mut std::io::BufReader::new(file)) {
-            Ok(secret_keys) => {
-                // Add all parsed secret keys.
-                for secret_key in secret_keys {
-                    self.add_tls_secret_key(secret_key);
-                }
-                // Return success.
-                Ok(())
-            }
-            // We don't use Err(_) here because if the error type of `rustls` changes in the
-            // future, we will get noticed.
-            //
-            // The `std::io` module has an error kind of `InvalidData` which is perfectly
-            // suitable for our kind of error.
-            Err(()) => Err(std::io::Error::new(
-                std::io::ErrorKind::InvalidData,
-                format!("cannot parse TLS private keys from {}", filename),
-            )),
-        }
+    /// Return the request timeout of the config.
+    pub fn request_timeout(&self) -> u64 {
+        self.request_timeout
     }
 
     /// Parse a config from a file.
@@ -277,10 +1157,145 @@ impl KeServerConfig {
                 }
             }
         };
+        // The timeouts of the phases of a connection default to the connection timeout.
+        let handshake_timeout = get_positive_int(&settings, "handshake_timeout", timeout)?;
+        let request_timeout = get_positive_int(&settings, "request_timeout", timeout)?;
 
         // Resolves metrics configuration.
         let metrics_config = get_metrics_config(&settings);
 
+        let next_server = get_next_server(&settings, "next_server")?;
+        let next_server_ipv4 = get_next_server(&settings, "next_server_ipv4")?;
+        let next_server_ipv6 = get_next_server(&settings, "next_server_ipv6")?;
+
+        let allowed_client_next_servers = match settings.get_array("allowed_client_next_servers") {
+            // If it's a not-found error, no client request will be honoured.
+            Err(config::ConfigError::NotFound(_)) => Vec::new(),
+            Err(error) => return Err(error),
+            Ok(servers) => {
+                let mut result = Vec::new();
+                for server in servers {
+                    result.push(validate_next_server(server.into_str()?)?);
+                }
+                result
+            }
+        };
+
+        let next_server_mappings = match settings.get_array("next_server_map") {
+            // If it's a not-found error, all clients get the same next server.
+            Err(config::ConfigError::NotFound(_)) => Vec::new(),
+            Err(error) => return Err(error),
+            Ok(mappings) => {
+                let mut result = Vec::new();
+                for mapping in mappings {
+                    result.push(parse_next_server_mapping(mapping)?);
+                }
+                result
+            }
+        };
+
+        let next_server_pool = match settings.get_array("next_server_pool") {
+            // If it's a not-found error, there is no pool.
+            Err(config::ConfigError::NotFound(_)) => None,
+            Err(error) => return Err(error),
+            Ok(values) => {
+                let mut members = Vec::new();
+                for value in values {
+                    members.push(parse_next_server_pool_member(value, next_port)?);
+                }
+                let max_failures = get_positive_int(&settings, "next_server_pool_max_failures", 3)?;
+                Some(NextServerPoolConfig {
+                    members,
+                    probe_interval: get_positive_int(
+                        &settings,
+                        "next_server_pool_probe_interval",
+                        10,
+                    )?,
+                    probe_timeout: get_positive_int(
+                        &settings,
+                        "next_server_pool_probe_timeout",
+                        2,
+                    )?,
+                    max_failures: u32::try_from(max_failures).map_err(|_| {
+                        config::ConfigError::Message(String::from(
+                            "next_server_pool_max_failures is not a valid u32",
+                        ))
+                    })?,
+                })
+            }
+        };
+
+        let mut rate_limit = RateLimitConfig::default();
+        if let Some(mut table) = get_optional_table(&settings, "rate_limit_per_ip")? {
+            rate_limit.per_ip = Some(parse_token_bucket(&mut table, "rate_limit_per_ip")?);
+        }
+        if let Some(mut table) = get_optional_table(&settings, "rate_limit_per_prefix")? {
+            rate_limit.per_prefix = Some(parse_token_bucket(&mut table, "rate_limit_per_prefix")?);
+            rate_limit.ipv4_prefix_len = parse_prefix_len(
+                &mut table,
+                "ipv4_prefix_len",
+                32,
+                rate_limit.ipv4_prefix_len,
+            )?;
+            rate_limit.ipv6_prefix_len = parse_prefix_len(
+                &mut table,
+                "ipv6_prefix_len",
+                128,
+                rate_limit.ipv6_prefix_len,
+            )?;
+        }
+
+        let max_connections = match settings.get_int("max_connections") {
+            // If it's a not-found error, the number of connections is unlimited.
+            Err(config::ConfigError::NotFound(_)) => None,
+            Err(error) => return Err(error),
+            Ok(val) => match usize::try_from(val) {
+                Ok(val) if val > 0 => Some(val),
+                _ => {
+                    return Err(config::ConfigError::Message(String::from(
+                        "the maximum number of connections is not a positive integer",
+                    )))
+                }
+            },
+        };
+
+        let shutdown_timeout = get_positive_int(&settings, "shutdown_timeout", 10)?;
+
+        let access_log = match settings.get_str("access_log") {
+            // If it's a not-found error, sessions are not logged.
+            Err(config::ConfigError::NotFound(_)) => None,
+            Err(error) => return Err(error),
+            Ok(destination) => Some(destination),
+        };
+
+        let proxy_protocol_trusted_sources =
+            match settings.get_array("proxy_protocol_trusted_sources") {
+                // If it's a not-found error, no load balancer is trusted.
+                Err(config::ConfigError::NotFound(_)) => Vec::new(),
+                Err(error) => return Err(error),
+                Ok(values) => values
+                    .into_iter()
+                    .map(|value| {
+                        value
+                            .into_str()?
+                            .parse()
+                            .map_err(config::ConfigError::Message)
+                    })
+                    .collect::<Result<Vec<Cidr>, _>>()?,
+            };
+
+        let workers = parse_workers(get_positive_int(&settings, "workers", 1)?)?;
+
+        let max_cookie_count =
+            usize::try_from(get_positive_int(&settings, "max_cookie_count", 16)?).map_err(
+                |_| config::ConfigError::Message(String::from("max_cookie_count is too large")),
+            )?;
+        let cookie_count = validate_cookie_count(
+            get_positive_int(&settings, "cookie_count", 8)?,
+            max_cookie_count,
+            "cookie_count",
+        )?;
+
         // Note that all of the file reading stuffs should be at the end of the function so that
         // all the not-file-related stuffs can fail fast.
 
@@ -288,6 +1303,21 @@ impl KeServerConfig {
         // Otherwise, cfnts will try to open the file while in the incorrect directory.
         let certs_filename = settings.get_str("tls_cert_file")?;
         let secret_keys_filename = settings.get_str("tls_key_file")?;
+        let ocsp_filename = match settings.get_str("tls_ocsp_file") {
+            // If it's a not-found error, nothing is stapled to the default certificate.
+            Err(config::ConfigError::NotFound(_)) => None,
+            Err(error) => return Err(error),
+            Ok(ocsp_filename) => Some(ocsp_filename),
+        };
+
+        let tls_client_auth = get_client_auth(&settings)?;
+        let tls_session_ticket_lifetime = get_session_ticket_lifetime(&settings)?;
+        let refuse_expired_cert = match settings.get_bool("tls_refuse_expired_cert") {
+            // If it's a not-found error, an expired certificate is only warned about.
+            Err(config::ConfigError::NotFound(_)) => false,
+            Err(error) => return Err(error),
+            Ok(refuse_expired_cert) => refuse_expired_cert,
+        };
 
         let cookie_key_filename = settings.get_str("cookie_key_file")?;
         let cookie_key = CookieKey::parse(&cookie_key_filename).wrap_err()?;
@@ -299,19 +1329,156 @@ impl KeServerConfig {
             metrics_config,
             next_port,
         );
+        config.config_file = Some(String::from(filename));
+        config.next_server = next_server;
+        config.next_server_ipv4 = next_server_ipv4;
+        config.next_server_ipv6 = next_server_ipv6;
+        config.allowed_client_next_servers = allowed_client_next_servers;
+        for mapping in next_server_mappings {
+            config.add_next_server_mapping(mapping);
+        }
+        config.next_server_pool = next_server_pool;
+        config.rate_limit = rate_limit;
+        config.max_connections = max_connections;
+        config.handshake_timeout = handshake_timeout;
+        config.request_timeout = request_timeout;
+        config.shutdown_timeout = shutdown_timeout;
+        config.access_log = access_log;
+        config.workers = workers;
+        config.tls_client_auth = tls_client_auth;
+        config.tls_session_ticket_lifetime = tls_session_ticket_lifetime;
+        config.cookie_count = cookie_count;
+        config.max_cookie_count = max_cookie_count;
+        config.proxy_protocol_trusted_sources = proxy_protocol_trusted_sources;
 
-        config.import_tls_certs(&certs_filename).wrap_err()?;
-        config
-            .import_tls_secret_keys(&secret_keys_filename)
-            .wrap_err()?;
+        config.add_tls_identity(
+            TlsIdentity::load(
+                Vec::new(),
+                certs_filename,
+                secret_keys_filename,
+                ocsp_filename,
+            )
+            .wrap_err()?,
+        );
+        match settings.get_array("tls_sni_certs") {
+            // If it's a not-found error, the default certificate is served to all clients.
+            Err(config::ConfigError::NotFound(_)) => (),
+            Err(error) => return Err(error),
+            Ok(values) => {
+                for value in values {
+                    config.add_tls_identity(parse_tls_sni_identity(value)?);
+                }
+            }
+        }
+        if refuse_expired_cert {
+            check_cert_expiry(&config.tls_identities)?;
+        }
 
         let addrs = settings.get_array("addr")?;
         for addr in addrs {
-            // Parse SocketAddr from a string.
-            let sock_addr = addr.to_string().parse().wrap_err()?;
-            config.add_address(sock_addr);
+            // An address can be either a plain string or a table with listener-specific options.
+            match addr.clone().into_table() {
+                Ok(mut table) => {
+                    let sock_addr: SocketAddr = match table.remove("addr") {
+                        Some(value) => value.into_str()?.parse().wrap_err()?,
+                        None => return Err(config::ConfigError::NotFound(String::from("addr"))),
+                    };
+                    if let Some(server) = table.remove("next_server") {
+                        let server = validate_next_server(server.into_str()?)?;
+                        config.set_listener_next_server(sock_addr, server);
+                    }
+                    if let Some(workers) = table.remove("workers") {
+                        let workers = match u64::try_from(workers.into_int()?) {
+                            Ok(workers) if workers > 0 => parse_workers(workers)?,
+                            _ => {
+                                return Err(config::ConfigError::Message(format!(
+                                    "the number of workers of {} is not a positive integer",
+                                    sock_addr
+                                )))
+                            }
+                        };
+                        config.set_listener_workers(sock_addr, workers);
+                    }
+                    if let Some(count) = table.remove("cookie_count") {
+                        let count = validate_cookie_count(
+                            u64::try_from(count.into_int()?).unwrap_or(0),
+                            max_cookie_count,
+                            &format!("the cookie count of {}", sock_addr),
+                        )?;
+                        config.set_listener_cookie_count(sock_addr, count);
+                    }
+                    if let Some(proxy_protocol) = table.remove("proxy_protocol") {
+                        if proxy_protocol.into_bool()? {
+                            // Without a trusted source, the listener would never read a header.
+                            if config.proxy_protocol_trusted_sources.is_empty() {
+                                return Err(config::ConfigError::Message(format!(
+                                    "proxy_protocol is enabled on {} but \
+                                     proxy_protocol_trusted_sources is empty",
+                                    sock_addr
+                                )));
+                            }
+                            config.enable_listener_proxy_protocol(sock_addr);
+                        }
+                    }
+                    if let Some(direct_clients) = table.remove("proxy_protocol_direct_clients") {
+                        if direct_clients.into_bool()? {
+                            config.allow_listener_proxy_direct_clients(sock_addr);
+                        }
+                    }
+                    if let Some(list) = parse_access_list(&mut table)? {
+                        config.set_listener_access_list(sock_addr, list);
+                    }
+                    if let Some(name) = table.remove("socket_name") {
+                        config.set_listener_socket_name(sock_addr, name.into_str()?);
+                    }
+                    config.add_address(sock_addr);
+                }
+                Err(_) => {
+                    // Parse SocketAddr from a string.
+                    let sock_addr = addr.to_string().parse().wrap_err()?;
+                    config.add_address(sock_addr);
+                }
+            }
         }
 
         Ok(config)
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_refuses_untrusted_source() {
+        let mut config = KeServerConfig::new(
+            1,
+            CookieKey::from(&[0; 32][..]),
+            String::from("memcache://localhost:11211"),
+            None,
+            123,
+        );
+        config.proxy_protocol_trusted_sources = vec!["192.0.2.0/24".parse().unwrap()];
+        let proxied = "[::]:4460".parse().unwrap();
+        let mixed = "[::]:4461".parse().unwrap();
+        let direct = "[::]:4462".parse().unwrap();
+        config.enable_listener_proxy_protocol(proxied);
+        config.enable_listener_proxy_protocol(mixed);
+        config.allow_listener_proxy_direct_clients(mixed);
+
+        let balancer = "192.0.2.1:40000".parse().unwrap();
+        let client = "198.51.100.1:40000".parse().unwrap();
+
+        // Only the load balancer may connect to a PROXY protocol listener by default.
+        assert!(!config.refuses_untrusted_source(&proxied, &balancer));
+        assert!(config.expects_proxy_header(&proxied, &balancer));
+        assert!(config.refuses_untrusted_source(&proxied, &client));
+
+        assert!(!config.refuses_untrusted_source(&mixed, &client));
+        assert!(!config.expects_proxy_header(&mixed, &client));
+
+        assert!(!config.refuses_untrusted_source(&direct, &client));
+        assert!(!config.refuses_untrusted_source(&direct, &balancer));
+        assert!(!config.expects_proxy_header(&direct, &balancer));
+    }
+}
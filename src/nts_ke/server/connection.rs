@@ -4,18 +4,27 @@
 
 //! NTS-KE server connection.
 
+use lazy_static::lazy_static;
+
 use mio::tcp::{Shutdown, TcpStream};
 
+use prometheus::{
+    __register_counter_vec, histogram_opts, opts, register_counter, register_histogram,
+    register_int_counter, register_int_counter_vec, Histogram, IntCounter, IntCounterVec,
+};
+
 use rustls::Session;
 
 use slog::{debug, error, info};
 
 use std::io::{Read, Write};
+use std::net::SocketAddr;
 use std::sync::{Arc, RwLock};
+use std::time::{Duration, Instant};
 
 use crate::cookie::{make_cookie, NTSKeys};
 use crate::key_rotator::KeyRotator;
-use crate::nts_ke::records::gen_key;
+use crate::nts_ke::records::{check_tls_negotiation, gen_key};
 use crate::nts_ke::records::{
     deserialize,
     process_record,
@@ -29,6 +38,8 @@ use crate::nts_ke::records::{
 
     EndOfMessageRecord,
     // Enums.
+    ErrorKind,
+    ErrorRecord,
     KnownAeadAlgorithm,
     KnownNextProtocol,
     NewCookieRecord,
@@ -36,6 +47,7 @@ use crate::nts_ke::records::{
     Party,
 
     PortRecord,
+    ServerRecord,
 
     // Structs.
     ReceivedNtsKeRecordState,
@@ -43,40 +55,213 @@ use crate::nts_ke::records::{
     // Constants.
     HEADER_SIZE,
 };
+use crate::x509;
 
+use super::access_log::SessionLog;
+use super::config::{ClientAuthMode, NextServerSource};
 use super::ke_server::KeServerState;
 use super::listener::KeServerListener;
+use super::proxy_protocol;
+use super::rate_limit::REJECTED_CONNECTION_COUNTER;
+
+lazy_static! {
+    static ref HANDSHAKE_COUNTER: IntCounter = register_int_counter!(
+        "nts_ke_tls_handshakes_total",
+        "Number of completed NTS-KE TLS handshakes, including resumed ones"
+    )
+    .unwrap();
+    static ref NEXT_SERVER_MAPPING_COUNTER: IntCounterVec = register_int_counter_vec!(
+        "nts_ke_next_server_mapping_total",
+        "Number of NTS-KE responses by the client network mapping used to choose the NTP server",
+        &["mapping"]
+    )
+    .unwrap();
+    static ref CLIENT_CERTIFICATE_COUNTER: IntCounterVec = register_int_counter_vec!(
+        "nts_ke_client_certificates_total",
+        "Number of NTS-KE handshakes by the result of the client certificate verification",
+        &["result"]
+    )
+    .unwrap();
+    static ref COOKIES_ISSUED_HISTOGRAM: Histogram = register_histogram!(
+        "nts_ke_cookies_issued",
+        "Number of cookies sent in an NTS-KE response",
+        vec![0.0, 1.0, 2.0, 4.0, 8.0, 16.0, 32.0, 64.0]
+    )
+    .unwrap();
+    static ref HANDSHAKE_FAILURE_COUNTER: IntCounterVec = register_int_counter_vec!(
+        "nts_ke_tls_handshake_failures_total",
+        "Number of NTS-KE connections closed before the TLS handshake was done, by reason",
+        &["reason"]
+    )
+    .unwrap();
+    static ref RECORD_COUNTER: IntCounterVec = register_int_counter_vec!(
+        "nts_ke_records_received_total",
+        "Number of NTS-KE records received, by record type",
+        &["type"]
+    )
+    .unwrap();
+    static ref RECORD_ERROR_COUNTER: IntCounterVec = register_int_counter_vec!(
+        "nts_ke_record_errors_total",
+        "Number of NTS-KE requests rejected because of a record, by error",
+        &["error"]
+    )
+    .unwrap();
+    static ref SESSION_COUNTER: IntCounterVec = register_int_counter_vec!(
+        "nts_ke_sessions_total",
+        "Number of NTS-KE sessions completed, by how they ended",
+        &["outcome"]
+    )
+    .unwrap();
+    static ref SESSION_DURATION_HISTOGRAM: Histogram = register_histogram!(
+        "nts_ke_session_duration_seconds",
+        "Time from accepting an NTS-KE connection to closing it",
+        vec![0.005, 0.01, 0.025, 0.05, 0.1, 0.25, 0.5, 1.0, 2.5, 5.0, 10.0, 30.0]
+    )
+    .unwrap();
+}
+
+/// Return the metrics label of a record type without the critical bit. The types are from RFC
+/// 8915 section 7.6.
+fn record_type_label(record_type: u16) -> &'static str {
+    match record_type {
+        0 => "end_of_message",
+        1 => "next_protocol",
+        2 => "error",
+        3 => "warning",
+        4 => "aead_algorithm",
+        5 => "new_cookie",
+        6 => "server",
+        7 => "port",
+        _ => "unknown",
+    }
+}
+
+/// Return the metrics label of a TLS error.
+fn tls_error_label(error: &rustls::TLSError) -> &'static str {
+    match error {
+        rustls::TLSError::AlertReceived(_) => "alert_received",
+        rustls::TLSError::PeerIncompatibleError(_) => "peer_incompatible",
+        rustls::TLSError::PeerMisbehavedError(_) => "peer_misbehaved",
+        rustls::TLSError::NoCertificatesPresented => "no_client_certificate",
+        rustls::TLSError::WebPKIError(_) => "bad_client_certificate",
+        rustls::TLSError::DecryptError => "decrypt_error",
+        rustls::TLSError::CorruptMessage | rustls::TLSError::CorruptMessagePayload(_) => {
+            "corrupt_message"
+        }
+        rustls::TLSError::InappropriateMessage { .. }
+        | rustls::TLSError::InappropriateHandshakeMessage { .. } => "unexpected_message",
+        _ => "other",
+    }
+}
+
+/// Split the first whole record, with its header, off `reader`. Return `None` and leave `reader`
+/// as it is if the record is not complete yet.
+fn next_record(reader: &mut &[u8]) -> Option<Vec<u8>> {
+    if reader.len() < HEADER_SIZE {
+        return None;
+    }
+    let body_length = u16::from_be_bytes([reader[2], reader[3]]) as usize;
+    if reader.len() < HEADER_SIZE + body_length {
+        return None;
+    }
+
+    let (record_bytes, rest) = reader.split_at(HEADER_SIZE + body_length);
+    *reader = rest;
+    Some(Vec::from(record_bytes))
+}
+
+/// Choose the next protocol and the AEAD algorithm for the received request, as described in
+/// RFC 8915 sections 4.1.2 and 4.1.5.
+///
+/// For both of them, we choose the first one in the client's preference order that we support.
+/// `None` means that there is nothing in common. In that case, the corresponding record in the
+/// response must be empty.
+///
+/// # Errors
+///
+/// Return the error code that should be sent to the client, if the request is malformed.
+///
+fn negotiate(
+    state: &ReceivedNtsKeRecordState,
+) -> Result<(Option<KnownNextProtocol>, Option<KnownAeadAlgorithm>), ErrorKind> {
+    // The Next Protocol record is mandatory in the request.
+    let protocol_ids = match &state.next_protocols {
+        Some(protocol_ids) => protocol_ids,
+        None => return Err(ErrorKind::BadRequest),
+    };
+
+    let protocol = protocol_ids
+        .iter()
+        .find_map(|id| KnownNextProtocol::from_protocol_id(*id));
+
+    let algorithm = match protocol {
+        // The AEAD Algorithm record is mandatory when NTPv4 is offered and it must contain at
+        // least one algorithm.
+        Some(KnownNextProtocol::Ntpv4) => match &state.aead_scheme {
+            Some(algorithm_ids) if !algorithm_ids.is_empty() => algorithm_ids
+                .iter()
+                .find_map(|id| KnownAeadAlgorithm::from_algorithm_id(*id)),
+            _ => return Err(ErrorKind::BadRequest),
+        },
+        None => None,
+    };
+
+    Ok((protocol, algorithm))
+}
 
 // response uses the configuration and the keys and computes the response
 // sent to the client.
-fn response(keys: NTSKeys, rotator: &Arc<RwLock<KeyRotator>>, port: u16) -> Vec<u8> {
+fn response(
+    protocol: Option<KnownNextProtocol>,
+    algorithm: Option<KnownAeadAlgorithm>,
+    keys: Option<NTSKeys>,
+    rotator: &Arc<RwLock<KeyRotator>>,
+    cookie_count: usize,
+    server: Option<String>,
+    port: u16,
+) -> Vec<u8> {
     let mut response: Vec<u8> = Vec::new();
 
-    let next_protocol_record = NextProtocolRecord::from(vec![KnownNextProtocol::Ntpv4]);
-    let aead_record = AeadAlgorithmRecord::from(vec![KnownAeadAlgorithm::AeadAesSivCmac256]);
-    let port_record = PortRecord::new(Party::Server, port);
-    let end_record = EndOfMessageRecord;
-
+    // If there is no protocol in common, the Next Protocol record is empty and that's the only
+    // record we send before the End of Message record.
+    let next_protocol_record = NextProtocolRecord::from(protocol.into_iter().collect::<Vec<_>>());
     response.append(&mut serialize(next_protocol_record));
-    response.append(&mut serialize(aead_record));
 
-    let rotor = rotator.read().unwrap();
-    let (key_id, actual_key) = rotor.latest_key_value();
+    if protocol.is_some() {
+        // If there is no algorithm in common, the AEAD Algorithm record is empty.
+        let aead_record = AeadAlgorithmRecord::from(algorithm.into_iter().collect::<Vec<_>>());
+        response.append(&mut serialize(aead_record));
+    }
 
-    // According to the spec, if the next protocol is NTPv4, we should send eight cookies to the
-    // client.
-    for _ in 0..8 {
-        let cookie = make_cookie(keys, actual_key.as_ref(), key_id);
-        let cookie_record = NewCookieRecord::from(cookie);
-        response.append(&mut serialize(cookie_record));
+    if let Some(keys) = keys {
+        let rotor = rotator.read().unwrap();
+        let (key_id, actual_key) = rotor.latest_key_value();
+
+        for _ in 0..cookie_count {
+            let cookie = make_cookie(keys, actual_key.as_ref(), key_id);
+            let cookie_record = NewCookieRecord::from(cookie);
+            response.append(&mut serialize(cookie_record));
+        }
+
+        if let Some(server) = server {
+            let server_record = ServerRecord::new(Party::Server, server);
+            response.append(&mut serialize(server_record));
+        }
+
+        let port_record = PortRecord::new(Party::Server, port);
+        response.append(&mut serialize(port_record));
     }
-    response.append(&mut serialize(port_record));
+
+    let end_record = EndOfMessageRecord;
     response.append(&mut serialize(end_record));
     response
 }
 
 #[derive(Clone, Copy, Eq, PartialEq)]
 pub enum KeServerConnState {
+    /// Waiting for the PROXY protocol header from a trusted load balancer. The TLS handshake
+    /// starts after it.
+    ReadingProxyHeader,
     /// The connection is just connected. The TLS handshake is not done yet.
     Connected,
     /// Doing the TLS handshake,
@@ -85,10 +270,28 @@ pub enum KeServerConnState {
     Opened,
     /// The response is sent after getting a good request.
     ResponseSent,
+    /// An Error record is sent after getting a bad request. The connection will be closed as soon
+    /// as the record is flushed.
+    ErrorSent,
     /// The connection is closed.
     Closed,
 }
 
+impl KeServerConnState {
+    /// Return the label used in logs and metrics.
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            KeServerConnState::ReadingProxyHeader => "reading_proxy_header",
+            KeServerConnState::Connected => "connected",
+            KeServerConnState::TlsHandshaking => "tls_handshaking",
+            KeServerConnState::Opened => "opened",
+            KeServerConnState::ResponseSent => "response_sent",
+            KeServerConnState::ErrorSent => "error_sent",
+            KeServerConnState::Closed => "closed",
+        }
+    }
+}
+
 /// NTS-KE server TCP connection.
 pub struct KeServerConn {
     /// Reference back to the corresponding `KeServer` state.
@@ -100,18 +303,36 @@ pub struct KeServerConn {
     /// The mio token for this connection.
     token: mio::Token,
 
+    /// Address of the client. For connections from a trusted load balancer, it's the address
+    /// from the PROXY protocol header once the header is read.
+    peer_addr: SocketAddr,
+
+    /// Address of the listener that accepted this connection.
+    listen_addr: SocketAddr,
+
     /// TLS session for this connection.
     tls_session: rustls::ServerSession,
 
     /// The status of the connection.
     state: KeServerConnState,
 
+    /// When the connection times out. Until the TLS handshake is done, it's the handshake
+    /// deadline. After that, it's the request deadline. `None` means that the timeout is too
+    /// large to represent.
+    deadline: Option<Instant>,
+
     /// The state of NTS-KE.
     ntske_state: ReceivedNtsKeRecordState,
 
     /// The buffer of NTS-KE Stream.
     ntske_buffer: Vec<u8>,
 
+    /// The bytes received while reading the PROXY protocol header.
+    proxy_buffer: Vec<u8>,
+
+    /// What happened in the session, for the access log.
+    session: SessionLog,
+
     /// Logger.
     logger: slog::Logger,
 }
@@ -120,24 +341,27 @@ impl KeServerConn {
     pub fn new(
         tcp_stream: TcpStream,
         token: mio::Token,
+        peer_addr: SocketAddr,
+        expects_proxy_header: bool,
         listener: &KeServerListener,
     ) -> KeServerConn {
         let server_state = listener.state();
 
         // Create a TLS session from a server-wide configuration.
-        let tls_session = rustls::ServerSession::new(&server_state.tls_server_config);
+        let tls_session = rustls::ServerSession::new(&server_state.tls_server_config());
         // Create a child logger for the connection.
         let logger = listener
             .logger()
-            .new(slog::o!("client" => listener.addr().to_string()));
+            .new(slog::o!("client" => peer_addr.to_string()));
 
         let ntske_state = ReceivedNtsKeRecordState {
             finished: false,
-            next_protocols: Vec::new(),
-            aead_scheme: Vec::new(),
+            next_protocols: None,
+            aead_scheme: None,
             cookies: Vec::new(),
             next_server: None,
             next_port: None,
+            warnings: Vec::new(),
         };
 
         KeServerConn {
@@ -146,9 +370,19 @@ impl KeServerConn {
             tcp_stream,
             tls_session,
             token,
-            state: KeServerConnState::Connected,
+            peer_addr,
+            listen_addr: *listener.addr(),
+            state: if expects_proxy_header {
+                KeServerConnState::ReadingProxyHeader
+            } else {
+                KeServerConnState::Connected
+            },
+            deadline: Instant::now()
+                .checked_add(Duration::from_secs(server_state.config.handshake_timeout())),
             ntske_state,
             ntske_buffer: Vec::new(),
+            proxy_buffer: Vec::new(),
+            session: SessionLog::new(),
             logger,
         }
     }
@@ -163,6 +397,11 @@ impl KeServerConn {
             self.write_ready();
         }
 
+        // After sending an Error record, we close the connection once everything is flushed.
+        if self.state() == KeServerConnState::ErrorSent && !self.tls_session.wants_write() {
+            self.shutdown();
+        }
+
         if self.state() != KeServerConnState::Closed {
             // TODO: Fix unwrap later.
             self.reregister(poll).unwrap();
@@ -170,6 +409,15 @@ impl KeServerConn {
     }
 
     fn read_ready(&mut self) {
+        // The bytes after the PROXY protocol header, if any, are the beginning of the TLS stream.
+        let mut after_proxy_header = None;
+        if self.state == KeServerConnState::ReadingProxyHeader {
+            match self.read_proxy_header() {
+                Some(rest) if !rest.is_empty() => after_proxy_header = Some(rest),
+                _ => return,
+            }
+        }
+
         // If this is the first time that `read_ready` is called, it means that we start reading
         // some TLS client hello from the client. So we need to change the state to TlsHandshaking.
         if self.state == KeServerConnState::Connected {
@@ -177,7 +425,10 @@ impl KeServerConn {
         }
 
         // Read some data from the stream and feed it to the TLS stream.
-        let result = self.tls_session.read_tls(&mut self.tcp_stream);
+        let result = match &after_proxy_header {
+            Some(rest) => self.tls_session.read_tls(&mut &rest[..]),
+            None => self.tls_session.read_tls(&mut self.tcp_stream),
+        };
 
         let read_count = match result {
             Ok(value) => value,
@@ -190,7 +441,7 @@ impl KeServerConn {
 
                 // Close the connection on error.
                 error!(self.logger, "read error: {}", error);
-                self.shutdown();
+                self.abort("io_error");
                 return;
             }
         };
@@ -198,7 +449,7 @@ impl KeServerConn {
         // If we reach the end-of-file, just close the connection.
         if read_count == 0 {
             info!(self.logger, "eof");
-            self.shutdown();
+            self.abort("eof");
             return;
         }
 
@@ -207,7 +458,32 @@ impl KeServerConn {
 
         if let Err(error) = processed {
             error!(self.logger, "cannot process packet: {}", error);
-            self.shutdown();
+            // Other handshake failures are counted when the connection is closed.
+            if self.tls_session.is_handshaking() {
+                HANDSHAKE_FAILURE_COUNTER
+                    .with_label_values(&[tls_error_label(&error)])
+                    .inc();
+                self.count_failed_client_certificate(&error);
+            }
+            self.abort("tls_error");
+            return;
+        }
+
+        // Once the handshake is done, the client has the request timeout to send its request and
+        // read the response.
+        if self.state == KeServerConnState::TlsHandshaking && !self.tls_session.is_handshaking() {
+            self.state = KeServerConnState::Opened;
+            self.deadline = Instant::now().checked_add(Duration::from_secs(
+                self.server_state.config.request_timeout(),
+            ));
+            HANDSHAKE_COUNTER.inc();
+            self.record_client_identity();
+
+            // A client that didn't agree to NTS-KE over TLS 1.3 doesn't get any keys.
+            if let Err(error) = check_tls_negotiation(&self.tls_session) {
+                info!(self.logger, "refusing session: {}", error);
+                self.send_error(ErrorKind::BadRequest);
+            }
         }
 
         let mut buf = Vec::new();
@@ -215,48 +491,39 @@ impl KeServerConn {
 
         if let Err(error) = result {
             error!(self.logger, "read failed: {}", error);
-            self.shutdown();
+            self.abort("tls_error");
             return;
         }
 
         if !buf.is_empty() {
+            // We already rejected the request. Anything else from the client is ignored.
+            if self.state == KeServerConnState::ErrorSent {
+                return;
+            }
+
             debug!(self.logger, "plaintext read {},", buf.len());
             self.ntske_buffer.append(&mut buf);
             let mut reader = &self.ntske_buffer[..];
 
-            // The plaintext is not empty. It means that the handshake is also done. We can change
-            // the state now.
-            if self.state == KeServerConnState::TlsHandshaking {
-                self.state = KeServerConnState::Opened;
-            }
-
-            let keys = gen_key(&self.tls_session).unwrap();
-
             while !self.ntske_state.finished {
-                // need to read 4 bytes to get the header.
-                if reader.len() < HEADER_SIZE {
-                    info!(
-                        self.logger,
-                        "readable nts-ke stream is not enough to read header"
-                    );
-                    self.ntske_buffer = Vec::from(reader);
-                    return;
-                }
-
-                // need to read the body_length to get the body.
-                let body_length = u16::from_be_bytes([reader[2], reader[3]]) as usize;
-                if reader.len() < HEADER_SIZE + body_length {
-                    info!(
-                        self.logger,
-                        "readable nts-ke stream is not enough to read body"
-                    );
-                    self.ntske_buffer = Vec::from(reader);
-                    return;
-                }
+                let record_bytes = match next_record(&mut reader) {
+                    Some(record_bytes) => record_bytes,
+                    None => {
+                        info!(
+                            self.logger,
+                            "readable nts-ke stream is not enough to read a record"
+                        );
+                        self.ntske_buffer = Vec::from(reader);
+                        return;
+                    }
+                };
 
-                // Reconstruct the whole record byte array to let the `records` module deserialize it.
-                let mut record_bytes = vec![0; HEADER_SIZE + body_length];
-                reader.read_exact(&mut record_bytes).unwrap();
+                // The type is recorded without the critical bit, once the whole record is here.
+                let record_type = u16::from_be_bytes([record_bytes[0], record_bytes[1]]) & 0x7fff;
+                self.session.records.push(record_type);
+                RECORD_COUNTER
+                    .with_label_values(&[record_type_label(record_type)])
+                    .inc();
 
                 match deserialize(Party::Server, record_bytes.as_slice()) {
                     Ok(record) => {
@@ -265,7 +532,8 @@ impl KeServerConn {
                             Ok(_) => {}
                             Err(err) => {
                                 error!(self.logger, "process nts-ke record: {}", err);
-                                self.shutdown();
+                                RECORD_ERROR_COUNTER.with_label_values(&["invalid"]).inc();
+                                self.send_error(ErrorKind::BadRequest);
                                 return;
                             }
                         }
@@ -275,15 +543,17 @@ impl KeServerConn {
                         debug!(self.logger, "unknown record type");
                     }
                     Err(DeserializeError::UnknownCriticalRecord) => {
-                        // TODO: This should propertly handled by sending an Error record.
                         debug!(self.logger, "error: unknown critical record");
-                        self.shutdown();
+                        RECORD_ERROR_COUNTER
+                            .with_label_values(&["unknown_critical"])
+                            .inc();
+                        self.send_error(ErrorKind::UnrecognizedCriticalRecord);
                         return;
                     }
                     Err(DeserializeError::Parsing(error)) => {
-                        // TODO: This shouldn't be wrapped as a trait object.
                         debug!(self.logger, "error: {}", error);
-                        self.shutdown();
+                        RECORD_ERROR_COUNTER.with_label_values(&["parse"]).inc();
+                        self.send_error(ErrorKind::BadRequest);
                         return;
                     }
                 }
@@ -291,24 +561,267 @@ impl KeServerConn {
 
             // We have to make sure that the response is not sent yet.
             if self.state == KeServerConnState::Opened {
+                let (protocol, algorithm) = match negotiate(&self.ntske_state) {
+                    Ok(negotiated) => negotiated,
+                    Err(kind) => {
+                        self.send_error(kind);
+                        return;
+                    }
+                };
+                debug!(
+                    self.logger,
+                    "negotiated protocol {:?} with algorithm {:?}", protocol, algorithm
+                );
+                self.session.next_protocol = protocol.map(|protocol| protocol.as_protocol_id());
+                self.session.aead_algorithm =
+                    algorithm.map(|algorithm| algorithm.as_algorithm_id());
+
+                // We can issue cookies only if both the protocol and the algorithm are agreed.
+                let keys = match (protocol, algorithm) {
+                    (Some(protocol), Some(algorithm)) => {
+                        match gen_key(&self.tls_session, protocol, algorithm) {
+                            Ok(keys) => Some(keys),
+                            Err(error) => {
+                                error!(self.logger, "cannot export keys: {}", error);
+                                self.send_error(ErrorKind::InternalServerError);
+                                return;
+                            }
+                        }
+                    }
+                    _ => None,
+                };
+
+                let next_server = self.server_state.config.next_server(
+                    &self.listen_addr,
+                    &self.peer_addr,
+                    self.ntske_state.next_server.as_deref(),
+                );
+                let pool_member = match (next_server.source, &self.server_state.next_server_pool) {
+                    // The pool replaces only the static servers. Client requests and client
+                    // network mappings are more specific.
+                    (NextServerSource::Static, Some(pool)) => pool.pick(),
+                    _ => None,
+                };
+                let (server, port, mapping) = match pool_member {
+                    Some(member) => (
+                        Some(member.server.as_str()),
+                        member.port,
+                        String::from("pool"),
+                    ),
+                    None => {
+                        let mapping = match next_server.source {
+                            NextServerSource::Client => String::from("client"),
+                            NextServerSource::Mapping(prefix) => prefix.to_string(),
+                            NextServerSource::Static => String::from("default"),
+                        };
+                        (next_server.server, next_server.port, mapping)
+                    }
+                };
+                NEXT_SERVER_MAPPING_COUNTER
+                    .with_label_values(&[&mapping])
+                    .inc();
+                debug!(
+                    self.logger,
+                    "referring to next server {:?} port {} from mapping {}", server, port, mapping
+                );
+                // The cookies and the next server are sent only with the keys.
+                let cookie_count = if keys.is_some() {
+                    self.session.next_server = server.map(String::from);
+                    self.session.next_port = Some(port);
+                    self.server_state.config.cookie_count(&self.listen_addr)
+                } else {
+                    0
+                };
+                self.session.cookies = cookie_count;
+                COOKIES_ISSUED_HISTOGRAM.observe(cookie_count as f64);
+
                 // TODO: Fix unwrap later.
                 self.tls_session
                     .write_all(&response(
+                        protocol,
+                        algorithm,
                         keys,
                         &self.server_state.rotator,
-                        self.server_state.config.next_port,
+                        cookie_count,
+                        server.map(String::from),
+                        port,
                     ))
                     .unwrap();
                 // Mark that the response is sent.
                 self.state = KeServerConnState::ResponseSent;
+                self.session.set_outcome("response_sent");
+            }
+        }
+    }
+
+    /// Count a handshake that failed because of the client certificate, if client certificates
+    /// are requested.
+    fn count_failed_client_certificate(&self, error: &rustls::TLSError) {
+        if self.server_state.config.tls_client_auth.mode == ClientAuthMode::Off {
+            return;
+        }
+        let result = match error {
+            rustls::TLSError::NoCertificatesPresented => "absent",
+            rustls::TLSError::WebPKIError(_) => "failed",
+            _ => return,
+        };
+        CLIENT_CERTIFICATE_COUNTER
+            .with_label_values(&[result])
+            .inc();
+    }
+
+    /// Take the identity of the client from its verified certificate, if client certificates are
+    /// requested, and add it to the logs and the access log.
+    ///
+    /// The metrics only say whether there was a certificate, because every certificate that the CA
+    /// issues would be a new time series otherwise.
+    fn record_client_identity(&mut self) {
+        if self.server_state.config.tls_client_auth.mode == ClientAuthMode::Off {
+            return;
+        }
+
+        // rustls has already verified the chain, so the first certificate is the client's own.
+        let (result, identity) = match self.tls_session.get_peer_certificates() {
+            Some(certs) => {
+                let name = certs
+                    .first()
+                    .and_then(|cert| x509::subject_common_name(&cert.0))
+                    .unwrap_or_else(|| String::from("unnamed"));
+                ("verified", name)
+            }
+            None => ("absent", String::from("anonymous")),
+        };
+
+        CLIENT_CERTIFICATE_COUNTER
+            .with_label_values(&[result])
+            .inc();
+        self.logger = self
+            .logger
+            .new(slog::o!("client_identity" => identity.clone()));
+        self.session.client_identity = Some(identity);
+    }
+
+    /// Read the PROXY protocol header from the stream and take the client address from it.
+    ///
+    /// Return the bytes received after the header once it's complete, or `None` if it's not
+    /// complete yet or the connection is closed.
+    fn read_proxy_header(&mut self) -> Option<Vec<u8>> {
+        let mut buf = [0; 512];
+        match self.tcp_stream.read(&mut buf) {
+            Ok(0) => {
+                info!(self.logger, "eof before the PROXY protocol header");
+                self.abort("eof");
+                return None;
             }
+            Ok(read_count) => self.proxy_buffer.extend_from_slice(&buf[..read_count]),
+            Err(error) => {
+                if error.kind() != std::io::ErrorKind::WouldBlock {
+                    error!(self.logger, "read error: {}", error);
+                    self.abort("io_error");
+                }
+                return None;
+            }
+        }
+
+        let header = match proxy_protocol::parse(&self.proxy_buffer) {
+            Ok(Some(header)) => header,
+            Ok(None) => return None,
+            Err(error) => {
+                error!(
+                    self.logger,
+                    "bad PROXY protocol header from {}: {}", self.peer_addr, error
+                );
+                self.abort("bad_proxy_header");
+                return None;
+            }
+        };
+
+        // Without a source address, the load balancer itself is the client, e.g. for health
+        // checks.
+        if let Some(source) = header.source {
+            info!(
+                self.logger,
+                "proxied connection from {} via {}", source, self.peer_addr
+            );
+            self.session.proxy_addr = Some(self.peer_addr);
+            self.peer_addr = source;
+            self.logger = self
+                .logger
+                .new(slog::o!("proxied_client" => source.to_string()));
+        }
+
+        // The load balancer isn't checked in the listener, so we do it for the real client.
+        if !self
+            .server_state
+            .access_lists
+            .admits(&self.listen_addr, &self.peer_addr.ip())
+        {
+            REJECTED_CONNECTION_COUNTER
+                .with_label_values(&["access_list"])
+                .inc();
+            debug!(
+                self.logger,
+                "refusing connection from {}: access_list", self.peer_addr
+            );
+            self.abort("access_denied");
+            return None;
+        }
+        let admitted = self
+            .server_state
+            .rate_limiter
+            .lock()
+            .unwrap()
+            .admit(&self.peer_addr.ip());
+        if let Err(rejection) = admitted {
+            REJECTED_CONNECTION_COUNTER
+                .with_label_values(&[rejection.as_str()])
+                .inc();
+            debug!(
+                self.logger,
+                "refusing connection from {}: {}",
+                self.peer_addr,
+                rejection.as_str()
+            );
+            self.abort("rate_limited");
+            return None;
+        }
+
+        self.state = KeServerConnState::Connected;
+        let rest = self.proxy_buffer.split_off(header.length);
+        self.proxy_buffer = Vec::new();
+        Some(rest)
+    }
+
+    /// Send an Error record followed by an End of Message record, as required by RFC 8915
+    /// section 4.1.3, and ask the TLS session to close.
+    fn send_error(&mut self, kind: ErrorKind) {
+        // A closed connection has nobody to send the error to, and reopening it would close it
+        // twice.
+        if self.state == KeServerConnState::Closed {
+            return;
         }
+        info!(self.logger, "sending nts-ke error: {}", kind);
+        self.session.error_code = Some(kind.as_code());
+
+        let mut response = serialize(ErrorRecord::new(kind));
+        response.append(&mut serialize(EndOfMessageRecord));
+
+        if let Err(error) = self.tls_session.write_all(&response) {
+            error!(self.logger, "cannot write error record: {}", error);
+            self.abort("io_error");
+            return;
+        }
+        self.tls_session.send_close_notify();
+        self.session.set_outcome("error_sent");
+
+        // The connection will be closed in `ready` after the records are flushed.
+        self.state = KeServerConnState::ErrorSent;
     }
 
     fn write_ready(&mut self) {
         if let Err(error) = self.tls_session.write_tls(&mut self.tcp_stream) {
             error!(self.logger, "write failed: {}", error);
-            self.shutdown();
+            self.abort("io_error");
         }
     }
 
@@ -348,9 +861,122 @@ impl KeServerConn {
         self.state
     }
 
+    /// Return when the connection times out in its current phase.
+    pub fn deadline(&self) -> Option<Instant> {
+        self.deadline
+    }
+
+    /// Close the connection and record `outcome` as how the session ended, unless it already
+    /// has one.
+    pub fn abort(&mut self, outcome: &'static str) {
+        self.session.set_outcome(outcome);
+        self.shutdown();
+    }
+
     pub fn shutdown(&mut self) {
+        // An error may close the connection before the handler of the same event finishes.
+        if self.state == KeServerConnState::Closed {
+            return;
+        }
+
+        let outcome = self.session.outcome.unwrap_or("closed");
+        let handshaking = matches!(
+            self.state,
+            KeServerConnState::Connected | KeServerConnState::TlsHandshaking
+        );
+        // TLS errors are counted with their details when they happen.
+        if handshaking && outcome != "tls_error" {
+            HANDSHAKE_FAILURE_COUNTER
+                .with_label_values(&[outcome])
+                .inc();
+        }
+        SESSION_COUNTER.with_label_values(&[outcome]).inc();
+        SESSION_DURATION_HISTOGRAM.observe(
+            self.session
+                .start
+                .elapsed()
+                .unwrap_or_default()
+                .as_secs_f64(),
+        );
+
         // TODO: Fix unwrap later.
         self.tcp_stream.shutdown(Shutdown::Both).unwrap();
         self.state = KeServerConnState::Closed;
+
+        if let Some(access_log) = &self.server_state.access_log {
+            let entry = self
+                .session
+                .to_json(&self.listen_addr, &self.peer_addr, &self.tls_session);
+            if let Err(error) = access_log.write(&entry) {
+                error!(self.logger, "cannot write the access log: {}", error);
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn request(
+        next_protocols: Option<Vec<u16>>,
+        aead_scheme: Option<Vec<u16>>,
+    ) -> ReceivedNtsKeRecordState {
+        ReceivedNtsKeRecordState {
+            finished: true,
+            next_protocols,
+            aead_scheme,
+            cookies: Vec::new(),
+            next_server: None,
+            next_port: None,
+            warnings: Vec::new(),
+        }
+    }
+
+    #[test]
+    fn test_negotiate() {
+        let ntpv4 = Some(KnownNextProtocol::Ntpv4);
+        let aes_siv = Some(KnownAeadAlgorithm::AeadAesSivCmac256);
+
+        // Unknown ids are skipped and the first supported one is chosen.
+        let state = request(Some(vec![0x8000, 0]), Some(vec![30, 15]));
+        assert_eq!(negotiate(&state), Ok((ntpv4, aes_siv)));
+
+        // Nothing in common.
+        let state = request(Some(vec![0x8000]), None);
+        assert_eq!(negotiate(&state), Ok((None, None)));
+        let state = request(Some(vec![0]), Some(vec![30]));
+        assert_eq!(negotiate(&state), Ok((ntpv4, None)));
+
+        // Missing mandatory records.
+        let state = request(None, Some(vec![15]));
+        assert_eq!(negotiate(&state), Err(ErrorKind::BadRequest));
+        let state = request(Some(vec![0]), None);
+        assert_eq!(negotiate(&state), Err(ErrorKind::BadRequest));
+        let state = request(Some(vec![0]), Some(vec![]));
+        assert_eq!(negotiate(&state), Err(ErrorKind::BadRequest));
+    }
+
+    #[test]
+    fn test_next_record_split() {
+        let mut bytes = serialize(PortRecord::new(Party::Server, 123));
+        bytes.append(&mut serialize(EndOfMessageRecord));
+
+        // The first read stops in the middle of the Port record, which is not taken until the
+        // rest of it arrives.
+        let mut buffer = Vec::from(&bytes[..HEADER_SIZE + 1]);
+        let mut reader = &buffer[..];
+        assert_eq!(next_record(&mut reader), None);
+        assert_eq!(reader.len(), HEADER_SIZE + 1);
+
+        buffer.extend_from_slice(&bytes[HEADER_SIZE + 1..]);
+        let mut reader = &buffer[..];
+        let mut records = Vec::new();
+        while let Some(record_bytes) = next_record(&mut reader) {
+            records.push(record_bytes);
+        }
+        assert_eq!(records.len(), 2);
+        assert_eq!(records[0], &bytes[..HEADER_SIZE + 2]);
+        assert!(reader.is_empty());
     }
 }
@@ -4,17 +4,30 @@
 
 //! NTS-KE server instantiation.
 
-use slog::info;
+use slog::{info, warn};
 
-use std::sync::{Arc, RwLock};
+use socket2::Type;
 
+use std::sync::atomic::AtomicUsize;
+use std::sync::{Arc, Mutex, RwLock};
+
+use crate::cfsock;
 use crate::key_rotator::periodic_rotate;
 use crate::key_rotator::KeyRotator;
 use crate::key_rotator::RotateError;
 use crate::metrics;
+use crate::nts_ke::records::ALPN_PROTOCOL;
+use crate::signal;
 
-use super::config::KeServerConfig;
+use super::access_list::{periodic_access_list_reload, AccessLists};
+use super::access_log::AccessLog;
+use super::cert_resolver::SniCertResolver;
+use super::config::{ClientAuthConfig, ClientAuthMode, KeServerConfig, TlsIdentity};
 use super::listener::KeServerListener;
+use super::pool::{periodic_probe, NextServerPool};
+use super::rate_limit::RateLimiter;
+use super::ticketer::RotatingTicketer;
+use super::tls_reload::periodic_tls_reload;
 
 /// NTS-KE server state that will be shared among listeners.
 pub(super) struct KeServerState {
@@ -32,7 +45,90 @@ pub(super) struct KeServerState {
     /// TLS server configuration which will be used among listeners.
     // We use `Arc` here so that every thread can read the config, but the drawback of using `Arc`
     // is that it uses garbage collection.
-    pub(super) tls_server_config: Arc<rustls::ServerConfig>,
+    //
+    // The config is swapped when the certificates are reloaded. Sessions that are already created
+    // keep their own reference to the old config.
+    tls_server_config: RwLock<Arc<rustls::ServerConfig>>,
+
+    /// Pool of NTP servers that clients are spread across, if it's configured. The health states
+    /// of the members are updated by a prober thread.
+    pub(super) next_server_pool: Option<Arc<NextServerPool>>,
+
+    /// Rate limiter of new connections. It's shared among listeners so that a client cannot get
+    /// more connections by connecting to many addresses.
+    pub(super) rate_limiter: Mutex<RateLimiter>,
+
+    /// Networks that may or may not connect to the listening addresses. They are replaced when
+    /// the configuration file is reloaded.
+    pub(super) access_lists: AccessLists,
+
+    /// Destination of the session access log, if it's configured.
+    pub(super) access_log: Option<AccessLog>,
+
+    /// Producer of TLS session tickets, if they are enabled. It's kept here, so that the reloaded
+    /// TLS configurations keep using it.
+    pub(super) ticketer: Option<Arc<RotatingTicketer>>,
+}
+
+impl KeServerState {
+    /// Return the current TLS server configuration.
+    pub(super) fn tls_server_config(&self) -> Arc<rustls::ServerConfig> {
+        self.tls_server_config.read().unwrap().clone()
+    }
+
+    /// Replace the TLS server configuration used by new sessions.
+    pub(super) fn set_tls_server_config(&self, tls_server_config: rustls::ServerConfig) {
+        *self.tls_server_config.write().unwrap() = Arc::new(tls_server_config);
+    }
+}
+
+/// Build the TLS server configuration with the certificate chains and private keys, the client
+/// authentication, and the session ticket producer.
+///
+/// # Errors
+///
+/// There will be an error if one of the certificates or the private keys is not usable, or the
+/// client CA certificates cannot be read.
+///
+pub(super) fn build_tls_server_config(
+    identities: &[TlsIdentity],
+    client_auth: &ClientAuthConfig,
+    ticketer: Option<&Arc<RotatingTicketer>>,
+) -> Result<rustls::ServerConfig, rustls::TLSError> {
+    let client_auth = match client_auth.mode {
+        ClientAuthMode::Off => rustls::NoClientAuth::new(),
+        mode => {
+            let roots = client_auth
+                .load_roots()
+                .map_err(|error| rustls::TLSError::General(error.to_string()))?;
+            if mode == ClientAuthMode::Required {
+                rustls::AllowAnyAuthenticatedClient::new(roots)
+            } else {
+                rustls::AllowAnyAnonymousOrAuthenticatedClient::new(roots)
+            }
+        }
+    };
+    // TLS server configuration.
+    let mut server_config = rustls::ServerConfig::new(client_auth);
+
+    // We support only TLS1.3
+    server_config.versions = vec![rustls::ProtocolVersion::TLSv1_3];
+
+    // Choose the certificate chain and its corresponding private key by SNI.
+    server_config.cert_resolver = Arc::new(SniCertResolver::new(identities)?);
+
+    // According to the NTS specification, ALPN protocol must be "ntske/1". The connections check
+    // that the clients have agreed to it.
+    server_config.set_protocols(&[Vec::from(ALPN_PROTOCOL)]);
+
+    // Tickets let clients resume their sessions on any server of the fleet. If they are disabled,
+    // we don't keep sessions in memory either, so clients cannot resume at all.
+    match ticketer {
+        Some(ticketer) => server_config.ticketer = ticketer.clone(),
+        None => server_config.session_storage = Arc::new(rustls::NoServerSessionStorage {}),
+    }
+
+    Ok(server_config)
 }
 
 /// NTS-KE server instance.
@@ -63,35 +159,56 @@ impl KeServer {
             config.logger().clone(),
         )?;
 
-        // Putting it in a block just to make it easier to read :)
-        let tls_server_config = {
-            // No client auth for TLS server.
-            let client_auth = rustls::NoClientAuth::new();
-            // TLS server configuration.
-            let mut server_config = rustls::ServerConfig::new(client_auth);
+        let rotator = Arc::new(RwLock::new(rotator));
+
+        let ticketer = config.tls_session_ticket_lifetime.map(|lifetime| {
+            // A ticket cannot be decrypted after its key leaves the rotator.
+            let retention = rotator.read().unwrap().retention();
+            let lifetime = if u64::from(lifetime) > retention {
+                warn!(
+                    config.logger(),
+                    "shortening the TLS session ticket lifetime to {} seconds, because the \
+                     ticket keys are kept only that long",
+                    retention
+                );
+                retention as u32
+            } else {
+                lifetime
+            };
+            Arc::new(RotatingTicketer::new(rotator.clone(), lifetime))
+        });
 
-            // We support only TLS1.3
-            server_config.versions = vec![rustls::ProtocolVersion::TLSv1_3];
+        let tls_server_config = build_tls_server_config(
+            &config.tls_identities,
+            &config.tls_client_auth,
+            ticketer.as_ref(),
+        )
+        .unwrap_or_else(|error| panic!("invalid key or certificate: {}", error));
 
-            // Set the certificate chain and its corresponding private key.
-            server_config
-                .set_single_cert(
-                    // rustls::ServerConfig wants to own both of them.
-                    config.tls_certs.clone(),
-                    config.tls_secret_keys[0].clone(),
-                )
-                .expect("invalid key or certificate");
+        let next_server_pool = config
+            .next_server_pool
+            .clone()
+            .map(|pool_config| Arc::new(NextServerPool::new(pool_config)));
 
-            // According to the NTS specification, ALPN protocol must be "ntske/1".
-            server_config.set_protocols(&[Vec::from("ntske/1".as_bytes())]);
+        let rate_limiter = Mutex::new(RateLimiter::new(&config.rate_limit));
 
-            server_config
-        };
+        let access_lists = AccessLists::new(config.access_lists().clone());
+
+        let access_log = config.access_log.as_ref().map(|destination| {
+            AccessLog::open(destination).unwrap_or_else(|error| {
+                panic!("cannot open the access log {}: {}", destination, error)
+            })
+        });
 
         let state = Arc::new(KeServerState {
             config,
-            rotator: Arc::new(RwLock::new(rotator)),
-            tls_server_config: Arc::new(tls_server_config),
+            rotator,
+            tls_server_config: RwLock::new(Arc::new(tls_server_config)),
+            next_server_pool,
+            rate_limiter,
+            access_lists,
+            access_log,
+            ticketer,
         });
 
         Ok(KeServer {
@@ -114,6 +231,28 @@ impl KeServer {
         // Create a new thread and periodically rotate the keys.
         periodic_rotate(mutable_rotator);
 
+        // Reload the certificates and the access lists on SIGHUP or when the files change.
+        if let Err(error) = signal::install_sighup_handler() {
+            warn!(logger, "cannot install the SIGHUP handler: {}", error);
+        }
+        periodic_tls_reload(self.state.clone());
+        periodic_access_list_reload(self.state.clone());
+
+        // Stop gracefully on SIGTERM or SIGINT.
+        if let Err(error) = signal::install_shutdown_handler() {
+            warn!(logger, "cannot install the shutdown handler: {}", error);
+        }
+
+        if let Some(pool) = &self.state.next_server_pool {
+            info!(logger, "spawning next server pool prober");
+
+            // Create a child logger to use inside the prober.
+            let log_pool = logger.new(slog::o!("component" => "next_server_pool"));
+
+            // Create a new thread and periodically probe the members of the pool.
+            periodic_probe(pool.clone(), log_pool);
+        }
+
         // We need to clone the metrics config here because we need to move it to another thread.
         if let Some(metrics_config) = self.state.config.metrics_config.clone() {
             info!(logger, "spawning metrics");
@@ -128,25 +267,67 @@ impl KeServer {
             });
         }
 
-        // For each address in the config, we will create a listener that will listen on that
-        // address. After the creation, we will create another thread and start listening inside
-        // that thread.
+        // For each address in the config, we will create as many listeners as the workers of that
+        // address. After the creation, we will create another thread for each of them and start
+        // listening inside that thread.
 
-        for addr in self.state.config.addrs() {
-            // Side-effect. Logging.
-            info!(logger, "starting NTS-KE server over TCP/TLS on {}", addr);
+        // The sockets that systemd has opened for us, if the server is socket-activated. The
+        // addresses without one bind their own sockets.
+        let mut inherited_sockets = cfsock::inherited_sockets()?;
 
-            // Instantiate a listener.
-            // If there is an error here just return an error immediately so that we don't have to
-            // start a thread for other address.
-            let listener = KeServerListener::bind(*addr, self)?;
+        for addr in self.state.config.addrs() {
+            let workers = self.state.config.workers(addr);
+            let inherited = cfsock::take_inherited_socket(
+                &mut inherited_sockets,
+                Type::STREAM,
+                addr,
+                self.state.config.socket_name(addr),
+            )?;
 
-            // It needs to be referenced by this thread and the new thread.
-            let atomic_listener = Arc::new(RwLock::new(listener));
+            // Side-effect. Logging.
+            info!(
+                logger,
+                "starting NTS-KE server over TCP/TLS on {} with {} workers", addr, workers;
+                "inherited_socket" => inherited.is_some()
+            );
+
+            // The workers of an address share the limit of concurrent connections.
+            let open_connections = Arc::new(AtomicUsize::new(0));
+
+            for worker in 0..workers {
+                // Instantiate a listener.
+                // If there is an error here just return an error immediately so that we don't
+                // have to start a thread for other address.
+                let listener = match &inherited {
+                    Some(socket) => KeServerListener::inherit(
+                        socket.try_clone()?.into(),
+                        *addr,
+                        worker,
+                        open_connections.clone(),
+                        self,
+                    )?,
+                    None => KeServerListener::bind(*addr, worker, open_connections.clone(), self)?,
+                };
+
+                // It needs to be referenced by this thread and the new thread.
+                let atomic_listener = Arc::new(RwLock::new(listener));
+
+                self.listeners.push(atomic_listener);
+            }
+        }
 
-            self.listeners.push(atomic_listener);
+        for socket in inherited_sockets {
+            warn!(
+                logger,
+                "closing an inherited socket that no address is configured for";
+                "name" => socket.name,
+                "addr" => socket.addr.map(|addr| addr.to_string())
+            );
         }
 
+        // All the addresses are bound, so we are ready for clients.
+        metrics::set_ready(true);
+
         // Join handles for the listeners.
         let mut handles = Vec::new();
 
@@ -178,6 +359,7 @@ impl KeServer {
             let _ = handle.join();
         }
 
+        info!(logger, "NTS-KE server is shut down");
         Ok(())
     }
 
@@ -4,23 +4,34 @@
 
 //! NTS-KE server listener.
 
+use lazy_static::lazy_static;
+
 use mio::net::TcpListener;
 
-use slog::{error, info};
+use prometheus::{
+    __register_counter_vec, opts, register_counter, register_int_counter, register_int_counter_vec,
+    IntCounter, IntCounterVec,
+};
+
+use slog::{debug, error, info, warn};
 
 use std::cmp::Reverse;
 use std::collections::BinaryHeap;
 use std::collections::HashMap;
 use std::net::SocketAddr;
+use std::sync::atomic::{AtomicUsize, Ordering};
 use std::sync::Arc;
-use std::time::{Duration, SystemTime};
+use std::time::{Duration, Instant};
 
 use crate::cfsock;
+use crate::metrics;
+use crate::signal;
 
 use super::connection::KeServerConn;
 use super::connection::KeServerConnState;
 use super::ke_server::KeServer;
 use super::ke_server::KeServerState;
+use super::rate_limit::REJECTED_CONNECTION_COUNTER;
 
 const LISTENER_MIO_TOKEN_ID: usize = 0;
 const CONNECTION_MIO_TOKEN_ID_MIN: usize = LISTENER_MIO_TOKEN_ID + 1;
@@ -30,6 +41,23 @@ const CONNECTION_MIO_TOKEN_ID_MAX: usize = usize::max_value() - 1;
 /// The token used to associate the mio event with the lister event.
 const LISTENER_MIO_TOKEN: mio::Token = mio::Token(LISTENER_MIO_TOKEN_ID);
 
+/// How long we wait for events before checking whether a shutdown is requested.
+const SHUTDOWN_CHECK_INTERVAL: Duration = Duration::from_millis(500);
+
+lazy_static! {
+    static ref CONNECTION_COUNTER: IntCounter = register_int_counter!(
+        "nts_ke_connections_total",
+        "Number of NTS-KE connections accepted, not counting the rejected ones"
+    )
+    .unwrap();
+    static ref TIMEOUT_COUNTER: IntCounterVec = register_int_counter_vec!(
+        "nts_ke_timeouts_total",
+        "Number of NTS-KE connections closed after a timeout by the phase they were in",
+        &["phase"]
+    )
+    .unwrap();
+}
+
 /// NTS-KE server internal listener for a specific listened address.
 /// One listener will correspond to one kernel listening socket.
 pub struct KeServerListener {
@@ -42,9 +70,14 @@ pub struct KeServerListener {
     /// List of connections accepted by this listener.
     connections: HashMap<mio::Token, KeServerConn>,
 
+    /// The number of open connections of all the workers of the address, which is what
+    /// `max_connections` limits.
+    open_connections: Arc<AtomicUsize>,
+
     /// Deadline indices for connections.
-    // We use `Reverse` because we want a min heap.
-    deadlines: BinaryHeap<Reverse<(SystemTime, mio::Token)>>,
+    // We use `Reverse` because we want a min heap. A connection gets a new entry when its deadline
+    // changes, so an entry is stale if it doesn't match the deadline of its connection anymore.
+    deadlines: BinaryHeap<Reverse<(Instant, mio::Token)>>,
 
     /// The next mio token id for a new connection.
     next_conn_token_id: usize,
@@ -52,6 +85,13 @@ pub struct KeServerListener {
     /// Address and port that this listener will listen to.
     addr: SocketAddr,
 
+    /// Whether the socket shares the address with other workers.
+    reuse_port: bool,
+
+    /// Whether the socket was passed by the service manager, in which case we cannot bind it
+    /// again.
+    inherited: bool,
+
     /// Polling object from mio.
     poll: mio::Poll,
 
@@ -62,15 +102,68 @@ pub struct KeServerListener {
 impl KeServerListener {
     /// Bind a new listener with the specified address and server.
     ///
+    /// `worker` is the index of the listener among the workers of the same address. If there are
+    /// more than one worker, the socket is bound with `SO_REUSEPORT`. All the workers share
+    /// `open_connections`.
+    ///
     /// # Errors
     ///
     /// All the errors here are from the kernel which we don't have to know about for now.
-    pub fn bind(addr: SocketAddr, server: &KeServer) -> Result<KeServerListener, std::io::Error> {
-        let state = server.state();
-        let poll = mio::Poll::new()?;
+    pub fn bind(
+        addr: SocketAddr,
+        worker: usize,
+        open_connections: Arc<AtomicUsize>,
+        server: &KeServer,
+    ) -> Result<KeServerListener, std::io::Error> {
+        let reuse_port = server.state().config.workers(&addr) > 1;
 
         // Create a listening std tcp listener.
-        let std_tcp_listener = cfsock::tcp_listener(&addr)?;
+        let std_tcp_listener = cfsock::tcp_listener(&addr, reuse_port)?;
+
+        KeServerListener::new(
+            std_tcp_listener,
+            addr,
+            worker,
+            open_connections,
+            server,
+            false,
+        )
+    }
+
+    /// Create a new listener for the specified address and server with a listening socket passed
+    /// by the service manager. The workers of the same address share the socket.
+    ///
+    /// # Errors
+    ///
+    /// All the errors here are from the kernel which we don't have to know about for now.
+    pub fn inherit(
+        std_tcp_listener: std::net::TcpListener,
+        addr: SocketAddr,
+        worker: usize,
+        open_connections: Arc<AtomicUsize>,
+        server: &KeServer,
+    ) -> Result<KeServerListener, std::io::Error> {
+        KeServerListener::new(
+            std_tcp_listener,
+            addr,
+            worker,
+            open_connections,
+            server,
+            true,
+        )
+    }
+
+    fn new(
+        std_tcp_listener: std::net::TcpListener,
+        addr: SocketAddr,
+        worker: usize,
+        open_connections: Arc<AtomicUsize>,
+        server: &KeServer,
+        inherited: bool,
+    ) -> Result<KeServerListener, std::io::Error> {
+        let state = server.state();
+        let poll = mio::Poll::new()?;
+        let reuse_port = !inherited && state.config.workers(&addr) > 1;
 
         // Transform a std tcp listener to a mio tcp listener.
         let mio_tcp_listener = TcpListener::from_std(std_tcp_listener)?;
@@ -88,27 +181,40 @@ impl KeServerListener {
             state: state.clone(),
             tcp_listener: mio_tcp_listener,
             connections: HashMap::new(),
+            open_connections,
             deadlines: BinaryHeap::new(),
             next_conn_token_id: CONNECTION_MIO_TOKEN_ID_MIN,
             addr,
-            // In the future, we may want to use the child logger instead the logger itself.
-            logger: state.config.logger().clone(),
+            reuse_port,
+            inherited,
+            logger: state
+                .config
+                .logger()
+                .new(slog::o!("listen_addr" => addr, "worker" => worker)),
             poll,
         })
     }
 
     /// Block the thread and start polling the events.
+    ///
+    /// It returns when a shutdown is requested and all the connections are finished, or the
+    /// shutdown timeout has passed.
     pub fn listen(&mut self) -> Result<(), std::io::Error> {
         // Holding up to 2048 events.
         let mut events = mio::Events::with_capacity(2048);
 
+        // The time when we stop waiting for in-flight connections. It's set when a shutdown is
+        // requested.
+        let mut drain_deadline = None;
+
         loop {
             // The error returned here is from the kernel select.
-            self.poll.poll(&mut events, None)?;
+            self.poll.poll(&mut events, Some(self.poll_timeout()))?;
+
+            // Close all expired connections, even if there is no event.
+            self.close_expired_connections();
 
             for event in events.iter() {
-                // Close all expired connections.
-                self.close_expired_connections();
                 let token = event.token();
 
                 // If the event is the listener event.
@@ -128,16 +234,59 @@ impl KeServerListener {
                 // The connection associated with the token may not exist for some reason. In which
                 // case, we just ignore it.
                 if let Some(connection) = self.connections.get_mut(&token) {
+                    let deadline = connection.deadline();
                     connection.ready(&mut self.poll, &event);
 
                     if connection.state() == KeServerConnState::Closed {
-                        self.connections.remove(&token);
+                        self.remove_connection(token);
+                    } else if connection.deadline() != deadline {
+                        // The connection moved to the next phase, which has its own timeout.
+                        if let Some(deadline) = connection.deadline() {
+                            self.deadlines.push(Reverse((deadline, token)));
+                        }
+                    }
+                }
+            }
+
+            if drain_deadline.is_none() && signal::shutdown_requested() {
+                drain_deadline = Some(self.start_draining()?);
+            }
+
+            if let Some(deadline) = drain_deadline {
+                if self.connections.is_empty() {
+                    info!(self.logger, "all connections on {} are finished", self.addr);
+                    return Ok(());
+                }
+                if Instant::now() >= deadline {
+                    warn!(
+                        self.logger,
+                        "closing {} connections on {} after the shutdown timeout",
+                        self.connections.len(),
+                        self.addr
+                    );
+                    for (_, mut connection) in self.connections.drain() {
+                        connection.abort("server_shutdown");
+                        self.open_connections.fetch_sub(1, Ordering::Relaxed);
                     }
+                    return Ok(());
                 }
             }
         }
     }
 
+    /// Stop accepting new connections and return the time until which the in-flight ones may
+    /// finish.
+    fn start_draining(&mut self) -> Result<Instant, std::io::Error> {
+        info!(
+            self.logger,
+            "shutdown requested, stop accepting connections on {}", self.addr
+        );
+        metrics::set_ready(false);
+        self.poll.deregister(&self.tcp_listener)?;
+
+        Ok(Instant::now() + Duration::from_secs(self.state.config.shutdown_timeout))
+    }
+
     /// Accepting a new connection. This will not block the thread, if it's called after receiving
     /// the `LISTENER_MIO_TOKEN` event. But it will block, if it's not.
     fn accept(&mut self) -> Result<(), std::io::Error> {
@@ -156,9 +305,15 @@ impl KeServerListener {
                     "encountered error while accepting connection; err={}", error
                 );
 
+                // We cannot bind a socket passed by the service manager again, so we keep using it.
+                if self.inherited {
+                    return Ok(());
+                }
+
                 // TODO: I don't understand why we need another tcp listener and register a new
                 // event here. I will figure it out after I finish refactoring everything.
-                self.tcp_listener = TcpListener::bind(&self.addr)?;
+                self.tcp_listener =
+                    TcpListener::from_std(cfsock::tcp_listener(&self.addr, self.reuse_port)?)?;
                 // TODO: Ignore error first. I wil figure out what to do later if there is an
                 // error.
                 self.poll.register(
@@ -175,29 +330,80 @@ impl KeServerListener {
 
         // Successfully accepting a connection.
 
+        // A trusted load balancer tells us the real client address in a PROXY protocol header.
+        // The connection rate-limits the client once it has read the header.
+        let expects_proxy_header = self.state.config.expects_proxy_header(&self.addr, &addr);
+
+        // Refuse the connection before spending anything on the TLS handshake, if the listener is
+        // full, or the client is not allowed or over its limit. Dropping the stream closes it.
+        if let Some(reason) = self.rejection_reason(&addr, expects_proxy_header) {
+            REJECTED_CONNECTION_COUNTER
+                .with_label_values(&[reason])
+                .inc();
+            debug!(self.logger, "refusing connection from {}: {}", addr, reason);
+            return Ok(());
+        }
+
         info!(self.logger, "accepting new connection from {}", addr);
+        CONNECTION_COUNTER.inc();
 
         let token = mio::Token(self.next_conn_token_id);
         self.increment_next_conn_token_id();
 
-        let timeout_duration = Duration::new(self.state.config.timeout(), 0);
+        // Create a new connection instance.
+        let connection = KeServerConn::new(tcp_stream, token, addr, expects_proxy_header, self);
 
-        // If the timeout is so large that we cannot put it in SystemTime, we can assume that
-        // it doesn't have a timeout and just don't add it into the map.
-        if let Some(timeout_systime) = SystemTime::now().checked_add(timeout_duration) {
-            self.deadlines.push(Reverse((timeout_systime, token)));
+        // If the timeout is so large that we cannot put it in Instant, we can assume that it
+        // doesn't have a timeout and just don't add it into the heap.
+        if let Some(deadline) = connection.deadline() {
+            self.deadlines.push(Reverse((deadline, token)));
         }
-
-        // Create a new connection instance.
-        let connection = KeServerConn::new(tcp_stream, token, self);
         // TODO: Fix the unwrap later.
         connection.register(&mut self.poll).unwrap();
 
         self.connections.insert(token, connection);
+        self.open_connections.fetch_add(1, Ordering::Relaxed);
 
         Ok(())
     }
 
+    /// Remove a connection from the listener and from the count of open connections.
+    fn remove_connection(&mut self, token: mio::Token) -> Option<KeServerConn> {
+        let connection = self.connections.remove(&token)?;
+        self.open_connections.fetch_sub(1, Ordering::Relaxed);
+        Some(connection)
+    }
+
+    /// Return the reason to refuse a new connection from `addr`, or `None` if it's admitted.
+    ///
+    /// Admitting a connection takes a token from the rate limiter, unless `addr` is a load
+    /// balancer whose connections are checked against the access list and rate-limited by the
+    /// client address in the PROXY protocol header instead.
+    fn rejection_reason(&self, addr: &SocketAddr, from_proxy: bool) -> Option<&'static str> {
+        // A client could skip the load balancer, and its policy, on a PROXY protocol listener.
+        if self.state.config.refuses_untrusted_source(&self.addr, addr) {
+            return Some("untrusted_source");
+        }
+        // The workers of the address count without a lock, so they may go over the limit together
+        // by at most one connection each.
+        if let Some(max_connections) = self.state.config.max_connections {
+            if self.open_connections.load(Ordering::Relaxed) >= max_connections {
+                return Some("max_connections");
+            }
+        }
+        if from_proxy {
+            return None;
+        }
+        if !self.state.access_lists.admits(&self.addr, &addr.ip()) {
+            return Some("access_list");
+        }
+
+        match self.state.rate_limiter.lock().unwrap().admit(&addr.ip()) {
+            Ok(()) => None,
+            Err(rejection) => Some(rejection.as_str()),
+        }
+    }
+
     /// Increment next_conn_token_id.
     fn increment_next_conn_token_id(&mut self) {
         match self.next_conn_token_id.checked_add(1) {
@@ -212,22 +418,41 @@ impl KeServerListener {
         }
     }
 
+    /// Return how long to wait for events: until the earliest deadline, but not longer than the
+    /// shutdown check interval.
+    fn poll_timeout(&self) -> Duration {
+        match self.deadlines.peek() {
+            Some(Reverse((deadline, _))) => deadline
+                .saturating_duration_since(Instant::now())
+                .min(SHUTDOWN_CHECK_INTERVAL),
+            None => SHUTDOWN_CHECK_INTERVAL,
+        }
+    }
+
     /// Closes the expired timeouts, looping until they are all gone.
     /// We remove the timeout from the heap, and kill the connection if it exists.
     fn close_expired_connections(&mut self) {
-        let now = SystemTime::now();
+        let now = Instant::now();
 
         while let Some(earliest) = self.deadlines.peek() {
-            let Reverse((deadline, token)) = earliest;
+            let Reverse((deadline, token)) = *earliest;
 
-            if deadline < &now {
+            if deadline <= now {
                 // If the deadline is already elapsed, close the connection and pop the heap.
                 // The connection associated with the token may not exist because, when we close
-                // the connection, it's not possible to find an entry in the heap. In which case,
-                // we can just pop the deadline heap.
-                if let Some(mut connection) = self.connections.remove(token) {
-                    error!(self.logger, "forcible shutdown after timeout");
-                    connection.shutdown();
+                // the connection, it's not possible to find an entry in the heap. It may also have
+                // moved to a later deadline. In which case, we can just pop the deadline heap.
+                let expired = self
+                    .connections
+                    .get(&token)
+                    .and_then(|connection| connection.deadline())
+                    == Some(deadline);
+                if expired {
+                    let mut connection = self.remove_connection(token).unwrap();
+                    let phase = connection.state().as_str();
+                    TIMEOUT_COUNTER.with_label_values(&[phase]).inc();
+                    error!(self.logger, "forcible shutdown after timeout"; "phase" => phase);
+                    connection.abort("timeout");
                 }
                 self.deadlines.pop();
 
@@ -4,10 +4,20 @@
 
 //! NTS-KE server implementation.
 
+mod access_list;
+mod access_log;
+mod cert_expiry;
+mod cert_resolver;
 mod config;
 mod connection;
 mod ke_server;
 mod listener;
+mod ocsp;
+mod pool;
+mod proxy_protocol;
+mod rate_limit;
+mod ticketer;
+mod tls_reload;
 
 // We expose only two structs: KeServer and KeServerConfig. KeServer is used to run an instant of
 // the NTS-KE server and KeServerConfig is used to instantiate KeServer.
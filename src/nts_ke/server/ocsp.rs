@@ -0,0 +1,200 @@
+// This file is part of cfnts.
+// Copyright (c) 2019, Cloudflare. All rights reserved.
+// See LICENSE for licensing information.
+
+//! OCSP responses stapled to the TLS certificates.
+//!
+//! An external tool fetches the responses and writes them to files, so the server never talks to
+//! the OCSP responders. We check just enough of a response to avoid stapling one that clients
+//! would reject: its status, the serial number of the certificate, and its validity. The
+//! signature is left to the clients. See RFC 6960 section 4.2.1 for the structure.
+
+use std::fs;
+use std::io;
+use std::time::SystemTime;
+
+use crate::der::{
+    expect_element, parse_time, read_element, TAG_GENERALIZED_TIME, TAG_INTEGER, TAG_OCTET_STRING,
+    TAG_OID, TAG_SEQUENCE,
+};
+use crate::x509;
+
+const TAG_ENUMERATED: u8 = 0x0a;
+const TAG_EXPLICIT_0: u8 = 0xa0;
+
+/// The certificate status "good", which is an implicitly tagged NULL.
+const TAG_STATUS_GOOD: u8 = 0x80;
+
+/// The object identifier of basic OCSP responses, 1.3.6.1.5.5.7.48.1.1.
+const OID_OCSP_BASIC: &[u8] = &[0x2b, 0x06, 0x01, 0x05, 0x05, 0x07, 0x30, 0x01, 0x01];
+
+/// An OCSP response read from a file.
+#[derive(Clone, Debug)]
+pub struct OcspResponse {
+    /// The response as it's stapled.
+    pub der: Vec<u8>,
+
+    /// When the response expires. `None` means that the responder didn't say.
+    pub next_update: Option<SystemTime>,
+}
+
+impl OcspResponse {
+    /// Read an OCSP response for `cert` from a DER file.
+    ///
+    /// # Errors
+    ///
+    /// There will be an error if we cannot read the file, the response is malformed or not
+    /// successful, or it doesn't say that the certificate is good.
+    ///
+    pub fn load(filename: &str, cert: &[u8]) -> Result<OcspResponse, io::Error> {
+        let der = fs::read(filename).map_err(|error| {
+            io::Error::new(error.kind(), format!("cannot read {}: {}", filename, error))
+        })?;
+        let serial = x509::serial_number(cert).unwrap_or_default();
+        let next_update = check(&der, serial).map_err(|reason| {
+            io::Error::new(
+                io::ErrorKind::InvalidData,
+                format!(
+                    "the OCSP response in {} is not usable: {}",
+                    filename, reason
+                ),
+            )
+        })?;
+        Ok(OcspResponse { der, next_update })
+    }
+
+    /// Return true if the response is expired at `now`.
+    pub fn is_expired(&self, now: SystemTime) -> bool {
+        matches!(self.next_update, Some(next_update) if next_update <= now)
+    }
+}
+
+/// One SingleResponse of a basic OCSP response.
+struct SingleResponse<'a> {
+    /// The serial number of the certificate.
+    serial: &'a [u8],
+
+    /// The tag of the certificate status.
+    status: u8,
+
+    /// When the status expires. `None` means that the responder didn't say.
+    next_update: Option<SystemTime>,
+}
+
+/// Return the SingleResponses of a BasicOCSPResponse, or `None` if it's malformed.
+fn single_responses(basic: &[u8]) -> Option<Vec<SingleResponse<'_>>> {
+    let (basic, _) = expect_element(basic, TAG_SEQUENCE)?;
+    let (data, _) = expect_element(basic, TAG_SEQUENCE)?;
+
+    // The version is optional and defaults to v1.
+    let data = match read_element(data)? {
+        (TAG_EXPLICIT_0, _, rest) => rest,
+        _ => data,
+    };
+    let (_responder_id, _, data) = read_element(data)?;
+    let (_produced_at, data) = expect_element(data, TAG_GENERALIZED_TIME)?;
+    let (mut responses, _) = expect_element(data, TAG_SEQUENCE)?;
+
+    let mut singles = Vec::new();
+    while !responses.is_empty() {
+        let (single, rest) = expect_element(responses, TAG_SEQUENCE)?;
+        responses = rest;
+
+        let (cert_id, single) = expect_element(single, TAG_SEQUENCE)?;
+        let (_hash_algorithm, cert_id) = expect_element(cert_id, TAG_SEQUENCE)?;
+        let (_issuer_name_hash, cert_id) = expect_element(cert_id, TAG_OCTET_STRING)?;
+        let (_issuer_key_hash, cert_id) = expect_element(cert_id, TAG_OCTET_STRING)?;
+        let (serial, _) = expect_element(cert_id, TAG_INTEGER)?;
+
+        let (status, _, single) = read_element(single)?;
+        let (_this_update, single) = expect_element(single, TAG_GENERALIZED_TIME)?;
+        let next_update = match read_element(single) {
+            Some((TAG_EXPLICIT_0, content, _)) => {
+                let (time, _) = expect_element(content, TAG_GENERALIZED_TIME)?;
+                Some(parse_time(TAG_GENERALIZED_TIME, time)?)
+            }
+            _ => None,
+        };
+
+        singles.push(SingleResponse {
+            serial,
+            status,
+            next_update,
+        });
+    }
+    Some(singles)
+}
+
+/// Check that a DER-encoded OCSP response says that the certificate with `serial` is good, and
+/// return when it expires.
+///
+/// # Errors
+///
+/// Return the reason if the response cannot be stapled.
+///
+fn check(der: &[u8], serial: &[u8]) -> Result<Option<SystemTime>, String> {
+    let malformed = || String::from("malformed response");
+
+    let (response, _) = expect_element(der, TAG_SEQUENCE).ok_or_else(malformed)?;
+    let (status, response) = expect_element(response, TAG_ENUMERATED).ok_or_else(malformed)?;
+    if status != [0] {
+        return Err(format!(
+            "the response status is {:?}, not successful",
+            status
+        ));
+    }
+
+    let (bytes, _) = expect_element(response, TAG_EXPLICIT_0).ok_or_else(malformed)?;
+    let (bytes, _) = expect_element(bytes, TAG_SEQUENCE).ok_or_else(malformed)?;
+    let (response_type, bytes) = expect_element(bytes, TAG_OID).ok_or_else(malformed)?;
+    if response_type != OID_OCSP_BASIC {
+        return Err(String::from("not a basic OCSP response"));
+    }
+    let (basic, _) = expect_element(bytes, TAG_OCTET_STRING).ok_or_else(malformed)?;
+
+    let singles = single_responses(basic).ok_or_else(malformed)?;
+    let single = singles
+        .iter()
+        .find(|single| single.serial == serial)
+        .ok_or_else(|| String::from("it's for another certificate"))?;
+    if single.status != TAG_STATUS_GOOD {
+        return Err(String::from("the certificate status is not good"));
+    }
+    Ok(single.next_update)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use rustls::internal::pemfile;
+
+    use std::fs::File;
+    use std::io::BufReader;
+    use std::time::{Duration, UNIX_EPOCH};
+
+    fn read_cert(filename: &str) -> Vec<u8> {
+        let file = File::open(filename).unwrap();
+        pemfile::certs(&mut BufReader::new(file))
+            .unwrap()
+            .remove(0)
+            .0
+    }
+
+    #[test]
+    fn test_load() {
+        let response = OcspResponse::load("tests/tls-ocsp.der", &read_cert("tests/tls.pem"));
+        let response = response.unwrap();
+
+        // The next update is in 2126.
+        let next_update = response.next_update.unwrap();
+        assert!(next_update > UNIX_EPOCH + Duration::from_secs(4_900_000_000));
+        assert!(!response.is_expired(SystemTime::now()));
+        assert!(response.is_expired(next_update));
+
+        // The response is for the leaf certificate only.
+        let intermediate = read_cert("tests/intermediate.pem");
+        assert!(OcspResponse::load("tests/tls-ocsp.der", &intermediate).is_err());
+        assert!(OcspResponse::load("tests/tls.pem", &intermediate).is_err());
+    }
+}
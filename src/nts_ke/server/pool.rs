@@ -0,0 +1,200 @@
+// This file is part of cfnts.
+// Copyright (c) 2019, Cloudflare. All rights reserved.
+// See LICENSE for licensing information.
+
+//! Health-checked pool of NTP servers that NTS-KE clients are referred to.
+
+use lazy_static::lazy_static;
+
+use prometheus::{__register_gauge_vec, opts, register_int_gauge_vec, IntGaugeVec};
+
+use rand::Rng;
+
+use slog::{info, warn};
+
+use std::io::{Error, ErrorKind};
+use std::net::{ToSocketAddrs, UdpSocket};
+use std::sync::atomic::{AtomicU32, Ordering};
+use std::sync::Arc;
+use std::thread;
+use std::time::Duration;
+
+use crate::ntp::protocol::{
+    parse_packet_header, serialize_ntp_packet, LeapState, NtpPacket, NtpPacketHeader, PacketMode,
+    VERSION,
+};
+
+use super::config::{NextServerPoolConfig, NextServerPoolMember};
+
+const BUFF_SIZE: usize = 2048;
+
+lazy_static! {
+    static ref MEMBER_HEALTHY_GAUGE: IntGaugeVec = register_int_gauge_vec!(
+        "nts_ke_next_server_pool_member_healthy",
+        "Whether a member of the next server pool passes its health check",
+        &["member"]
+    )
+    .unwrap();
+}
+
+/// Send a plain NTP client query to the member and check that it answers like a synchronized
+/// server.
+///
+/// # Errors
+///
+/// There will be an error if the member cannot be resolved or reached, doesn't answer in time, or
+/// answers with a packet that doesn't match the query or says that it's not synchronized.
+///
+fn probe(member: &NextServerPoolMember, timeout: Duration) -> Result<(), Error> {
+    let addr = match (member.server.as_str(), member.port)
+        .to_socket_addrs()?
+        .next()
+    {
+        Some(addr) => addr,
+        None => return Err(Error::new(ErrorKind::NotFound, "no address found")),
+    };
+
+    let socket = if addr.is_ipv4() {
+        UdpSocket::bind("0.0.0.0:0")?
+    } else {
+        UdpSocket::bind("[::]:0")?
+    };
+    socket.set_read_timeout(Some(timeout))?;
+    socket.set_write_timeout(Some(timeout))?;
+    socket.connect(addr)?;
+
+    // The server has to copy our transmit timestamp into its origin timestamp, so a random one
+    // lets us match the response with the query.
+    let transmit_timestamp = rand::thread_rng().gen();
+    let query = NtpPacket {
+        header: NtpPacketHeader {
+            leap_indicator: LeapState::NoLeap,
+            version: VERSION,
+            mode: PacketMode::Client,
+            stratum: 0,
+            poll: 0,
+            precision: 0x20,
+            root_delay: 0,
+            root_dispersion: 0,
+            reference_id: 0,
+            reference_timestamp: 0,
+            origin_timestamp: 0,
+            receive_timestamp: 0,
+            transmit_timestamp,
+        },
+        exts: Vec::new(),
+    };
+    socket.send(&serialize_ntp_packet(query))?;
+
+    let mut buff = [0; BUFF_SIZE];
+    let size = socket.recv(&mut buff)?;
+    let header = parse_packet_header(&buff[..size])?;
+
+    if header.mode != PacketMode::Server || header.origin_timestamp != transmit_timestamp {
+        return Err(Error::new(
+            ErrorKind::InvalidData,
+            "the response doesn't match the query",
+        ));
+    }
+    // Stratum 0 is a Kiss-o'-Death packet and 16 means unsynchronized, according to RFC 5905.
+    if header.leap_indicator == LeapState::Unknown || header.stratum == 0 || header.stratum >= 16 {
+        return Err(Error::new(
+            ErrorKind::InvalidData,
+            "the server is not synchronized",
+        ));
+    }
+
+    Ok(())
+}
+
+/// Pool of NTP servers with their health states.
+pub(super) struct NextServerPool {
+    config: NextServerPoolConfig,
+
+    /// The number of consecutive failed probes of each member, in the same order as
+    /// `config.members`. A member is healthy while it's below `config.max_failures`.
+    // Members start healthy so that clients get referred to the pool before the first probes
+    // finish.
+    failures: Vec<AtomicU32>,
+}
+
+impl NextServerPool {
+    /// Create a pool in which all members are healthy.
+    pub(super) fn new(config: NextServerPoolConfig) -> NextServerPool {
+        let failures = config.members.iter().map(|_| AtomicU32::new(0)).collect();
+        NextServerPool { config, failures }
+    }
+
+    fn is_healthy(&self, index: usize) -> bool {
+        self.failures[index].load(Ordering::Relaxed) < self.config.max_failures
+    }
+
+    /// Choose a healthy member randomly according to the weights.
+    ///
+    /// Return `None` if no member is healthy.
+    pub(super) fn pick(&self) -> Option<&NextServerPoolMember> {
+        let healthy: Vec<&NextServerPoolMember> = self
+            .config
+            .members
+            .iter()
+            .enumerate()
+            .filter(|(index, _)| self.is_healthy(*index))
+            .map(|(_, member)| member)
+            .collect();
+
+        let total: u64 = healthy.iter().map(|member| u64::from(member.weight)).sum();
+        if total == 0 {
+            return None;
+        }
+
+        let mut point = rand::thread_rng().gen_range(0, total);
+        for member in healthy {
+            let weight = u64::from(member.weight);
+            if point < weight {
+                return Some(member);
+            }
+            point -= weight;
+        }
+        // The point is always less than the total weight.
+        unreachable!()
+    }
+
+    /// Probe all members once and update their health states.
+    fn probe_all(&self, logger: &slog::Logger) {
+        let timeout = Duration::from_secs(self.config.probe_timeout);
+
+        for (index, member) in self.config.members.iter().enumerate() {
+            let label = format!("{}:{}", member.server, member.port);
+            let was_healthy = self.is_healthy(index);
+
+            match probe(member, timeout) {
+                Ok(()) => {
+                    self.failures[index].store(0, Ordering::Relaxed);
+                    if !was_healthy {
+                        info!(logger, "next server {} is healthy again", label);
+                    }
+                }
+                Err(error) => {
+                    // It's fine to be a bit racy here because there is only one prober.
+                    let failures = self.failures[index].load(Ordering::Relaxed);
+                    self.failures[index].store(failures.saturating_add(1), Ordering::Relaxed);
+                    if was_healthy && !self.is_healthy(index) {
+                        warn!(logger, "next server {} is unhealthy: {}", label, error);
+                    }
+                }
+            }
+
+            MEMBER_HEALTHY_GAUGE
+                .with_label_values(&[&label])
+                .set(self.is_healthy(index) as i64);
+        }
+    }
+}
+
+/// Create a new thread and periodically probe the members of the pool.
+pub(super) fn periodic_probe(pool: Arc<NextServerPool>, logger: slog::Logger) {
+    thread::spawn(move || loop {
+        pool.probe_all(&logger);
+        thread::sleep(Duration::from_secs(pool.config.probe_interval));
+    });
+}
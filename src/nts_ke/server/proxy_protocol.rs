@@ -0,0 +1,237 @@
+// This file is part of cfnts.
+// Copyright (c) 2019, Cloudflare. All rights reserved.
+// See LICENSE for licensing information.
+
+//! PROXY protocol header parsing.
+//!
+//! Load balancers send the header before the proxied stream to tell us the original client
+//! address. Both the version 1 text format and the version 2 binary format are supported. See
+//! https://www.haproxy.org/download/2.0/doc/proxy-protocol.txt for the specification.
+
+use std::net::{IpAddr, Ipv4Addr, Ipv6Addr, SocketAddr};
+use std::str::FromStr;
+
+/// The prefix of a version 1 header.
+const V1_PREFIX: &[u8] = b"PROXY ";
+
+/// The maximum length of a version 1 header, including the CRLF.
+const V1_MAX_LEN: usize = 107;
+
+/// The signature at the beginning of a version 2 header.
+const V2_SIGNATURE: &[u8] = b"\r\n\r\n\x00\r\nQUIT\n";
+
+/// The length of the fixed part of a version 2 header.
+const V2_FIXED_LEN: usize = 16;
+
+/// The maximum length of the variable part of a version 2 header that we accept. Load balancers
+/// don't send large TLVs, so a larger header is most likely garbage.
+const V2_MAX_ADDRESS_LEN: usize = 4096;
+
+/// A parsed PROXY protocol header.
+#[derive(Debug, Eq, PartialEq)]
+pub(super) struct ProxyHeader {
+    /// The length of the header. The proxied stream starts right after it.
+    pub(super) length: usize,
+
+    /// The original client address. It's `None` if the load balancer doesn't proxy a client, for
+    /// example, for its own health checks.
+    pub(super) source: Option<SocketAddr>,
+}
+
+/// Parse a PROXY protocol header at the beginning of `buf`.
+///
+/// Return `Ok(None)` if `buf` is a prefix of a header and we need more bytes.
+///
+/// # Errors
+///
+/// There will be an error if `buf` doesn't start with a valid header.
+///
+pub(super) fn parse(buf: &[u8]) -> Result<Option<ProxyHeader>, String> {
+    if starts_with_prefix(buf, V2_SIGNATURE) {
+        if buf.len() < V2_SIGNATURE.len() {
+            return Ok(None);
+        }
+        parse_v2(buf)
+    } else if starts_with_prefix(buf, V1_PREFIX) {
+        if buf.len() < V1_PREFIX.len() {
+            return Ok(None);
+        }
+        parse_v1(buf)
+    } else {
+        Err(String::from(
+            "the stream doesn't start with a PROXY protocol header",
+        ))
+    }
+}
+
+/// Return true if `buf` and `prefix` agree on their common length.
+fn starts_with_prefix(buf: &[u8], prefix: &[u8]) -> bool {
+    let len = buf.len().min(prefix.len());
+    buf[..len] == prefix[..len]
+}
+
+/// Parse a header like `PROXY TCP4 192.0.2.1 198.51.100.1 56324 4460\r\n`.
+fn parse_v1(buf: &[u8]) -> Result<Option<ProxyHeader>, String> {
+    let end = match buf.windows(2).position(|window| window == b"\r\n") {
+        Some(end) => end,
+        None if buf.len() < V1_MAX_LEN => return Ok(None),
+        None => return Err(String::from("the PROXY protocol v1 header is too long")),
+    };
+    let length = end + 2;
+    if length > V1_MAX_LEN {
+        return Err(String::from("the PROXY protocol v1 header is too long"));
+    }
+
+    let line = std::str::from_utf8(&buf[..end])
+        .map_err(|_| String::from("the PROXY protocol v1 header is not ascii"))?;
+    let fields: Vec<&str> = line.split(' ').collect();
+
+    match fields.get(1).copied() {
+        // The load balancer couldn't tell the client address. We have to ignore the rest.
+        Some("UNKNOWN") => Ok(Some(ProxyHeader {
+            length,
+            source: None,
+        })),
+        Some("TCP4") | Some("TCP6") if fields.len() == 6 => {
+            let invalid = || format!("invalid PROXY protocol v1 header: {}", line);
+            let ip = IpAddr::from_str(fields[2]).map_err(|_| invalid())?;
+            let port = u16::from_str(fields[4]).map_err(|_| invalid())?;
+            if ip.is_ipv4() != (fields[1] == "TCP4") {
+                return Err(invalid());
+            }
+            Ok(Some(ProxyHeader {
+                length,
+                source: Some(SocketAddr::new(ip, port)),
+            }))
+        }
+        _ => Err(format!("invalid PROXY protocol v1 header: {}", line)),
+    }
+}
+
+/// Parse a binary header that starts with `V2_SIGNATURE`.
+fn parse_v2(buf: &[u8]) -> Result<Option<ProxyHeader>, String> {
+    if buf.len() < V2_FIXED_LEN {
+        return Ok(None);
+    }
+
+    let version_command = buf[12];
+    let family_protocol = buf[13];
+    let address_len = u16::from_be_bytes([buf[14], buf[15]]) as usize;
+
+    if version_command >> 4 != 2 {
+        return Err(format!(
+            "unsupported PROXY protocol version {}",
+            version_command >> 4
+        ));
+    }
+    if address_len > V2_MAX_ADDRESS_LEN {
+        return Err(String::from("the PROXY protocol v2 header is too long"));
+    }
+
+    let length = V2_FIXED_LEN + address_len;
+    if buf.len() < length {
+        return Ok(None);
+    }
+    let address = &buf[V2_FIXED_LEN..length];
+
+    let source = match version_command & 0x0f {
+        // LOCAL: the connection is made by the load balancer itself.
+        0x0 => None,
+        // PROXY: the connection is proxied for a client.
+        0x1 => match family_protocol {
+            // TCP over IPv4.
+            0x11 if address.len() >= 12 => {
+                let ip = Ipv4Addr::new(address[0], address[1], address[2], address[3]);
+                let port = u16::from_be_bytes([address[8], address[9]]);
+                Some(SocketAddr::new(IpAddr::V4(ip), port))
+            }
+            // TCP over IPv6.
+            0x21 if address.len() >= 36 => {
+                let mut octets = [0; 16];
+                octets.copy_from_slice(&address[..16]);
+                let port = u16::from_be_bytes([address[32], address[33]]);
+                Some(SocketAddr::new(IpAddr::V6(Ipv6Addr::from(octets)), port))
+            }
+            // Unspecified or non-TCP families. We have to ignore the addresses.
+            0x00 => None,
+            _ => {
+                return Err(format!(
+                    "unsupported PROXY protocol v2 address family {:#04x}",
+                    family_protocol
+                ))
+            }
+        },
+        command => return Err(format!("unsupported PROXY protocol v2 command {}", command)),
+    };
+
+    Ok(Some(ProxyHeader { length, source }))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_parse_v1() {
+        let header = b"PROXY TCP4 192.0.2.1 198.51.100.1 56324 4460\r\n";
+        let mut stream = header.to_vec();
+        stream.extend_from_slice(b"\x16\x03\x01");
+
+        assert_eq!(
+            parse(&stream),
+            Ok(Some(ProxyHeader {
+                length: header.len(),
+                source: Some("192.0.2.1:56324".parse().unwrap()),
+            }))
+        );
+        assert_eq!(parse(&header[..20]), Ok(None));
+        assert_eq!(parse(b"PRO"), Ok(None));
+
+        let header = b"PROXY TCP6 2001:db8::1 2001:db8::2 56324 4460\r\n";
+        assert_eq!(
+            parse(header).unwrap().unwrap().source,
+            Some("[2001:db8::1]:56324".parse().unwrap())
+        );
+
+        let header = b"PROXY UNKNOWN\r\n";
+        assert_eq!(parse(header).unwrap().unwrap().source, None);
+
+        assert!(parse(b"PROXY TCP4 2001:db8::1 192.0.2.1 1 2\r\n").is_err());
+        assert!(parse(b"PROXY TCP4 192.0.2.1\r\n").is_err());
+        assert!(parse(b"\x16\x03\x01").is_err());
+        assert!(parse(&[b'P'; 200][..]).is_err());
+    }
+
+    #[test]
+    fn test_parse_v2() {
+        let mut header = V2_SIGNATURE.to_vec();
+        header.extend_from_slice(&[0x21, 0x11, 0x00, 0x0c]);
+        header.extend_from_slice(&[192, 0, 2, 1, 198, 51, 100, 1, 0xdc, 0x04, 0x11, 0x6c]);
+
+        assert_eq!(
+            parse(&header),
+            Ok(Some(ProxyHeader {
+                length: 28,
+                source: Some("192.0.2.1:56324".parse().unwrap()),
+            }))
+        );
+        assert_eq!(parse(&header[..20]), Ok(None));
+        assert_eq!(parse(&header[..5]), Ok(None));
+
+        // A LOCAL header has no client address.
+        let mut header = V2_SIGNATURE.to_vec();
+        header.extend_from_slice(&[0x20, 0x00, 0x00, 0x00]);
+        assert_eq!(
+            parse(&header),
+            Ok(Some(ProxyHeader {
+                length: 16,
+                source: None,
+            }))
+        );
+
+        // Version 3 doesn't exist.
+        let mut header = V2_SIGNATURE.to_vec();
+        header.extend_from_slice(&[0x31, 0x11, 0x00, 0x00]);
+        assert!(parse(&header).is_err());
+    }
+}
@@ -0,0 +1,256 @@
+// This file is part of cfnts.
+// Copyright (c) 2019, Cloudflare. All rights reserved.
+// See LICENSE for licensing information.
+
+//! Rate limiting of new NTS-KE connections by client address and network.
+
+use lazy_static::lazy_static;
+
+use prometheus::{__register_counter_vec, opts, register_int_counter_vec, IntCounterVec};
+
+use std::collections::{HashMap, VecDeque};
+use std::net::IpAddr;
+use std::time::{Duration, Instant};
+
+use crate::cidr::{canonical_ip, Cidr};
+
+use super::config::{RateLimitConfig, TokenBucketConfig};
+
+lazy_static! {
+    pub(super) static ref REJECTED_CONNECTION_COUNTER: IntCounterVec = register_int_counter_vec!(
+        "nts_ke_rejected_connections_total",
+        "Number of NTS-KE connections refused before the TLS handshake by reason",
+        &["reason"]
+    )
+    .unwrap();
+}
+
+/// The maximum number of buckets of a kind of source. When a new source comes in at the limit,
+/// the oldest bucket is dropped, so that many source addresses cannot use up the memory.
+const MAX_BUCKETS: usize = 65536;
+
+/// How often full buckets are dropped. The whole map is scanned, so it's not done on every new
+/// connection.
+const CLEANUP_INTERVAL: Duration = Duration::from_secs(1);
+
+/// Why a new connection is refused.
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+pub(super) enum Rejection {
+    /// The client address has run out of tokens.
+    IpRate,
+    /// The client network has run out of tokens.
+    PrefixRate,
+}
+
+impl Rejection {
+    /// Return the label used in metrics.
+    pub(super) fn as_str(&self) -> &'static str {
+        match self {
+            Rejection::IpRate => "ip_rate",
+            Rejection::PrefixRate => "prefix_rate",
+        }
+    }
+}
+
+struct TokenBucket {
+    tokens: f64,
+    last_refill: Instant,
+}
+
+impl TokenBucket {
+    /// Add the tokens earned since the last refill and return the current number of tokens.
+    fn refill(&mut self, config: &TokenBucketConfig, now: Instant) -> f64 {
+        let elapsed = now.saturating_duration_since(self.last_refill);
+        self.tokens = (self.tokens + elapsed.as_secs_f64() * config.rate).min(config.burst);
+        self.last_refill = now;
+        self.tokens
+    }
+}
+
+/// Token buckets of a kind of source.
+struct Buckets {
+    config: TokenBucketConfig,
+    buckets: HashMap<Cidr, TokenBucket>,
+    /// The sources of `buckets` from the oldest to the newest.
+    order: VecDeque<Cidr>,
+    last_cleanup: Instant,
+}
+
+impl Buckets {
+    fn new(config: TokenBucketConfig) -> Buckets {
+        Buckets {
+            config,
+            buckets: HashMap::new(),
+            order: VecDeque::new(),
+            last_cleanup: Instant::now(),
+        }
+    }
+
+    /// Return true if the source has a token to take.
+    fn has_token(&mut self, source: Cidr, now: Instant) -> bool {
+        let config = &self.config;
+        match self.buckets.get_mut(&source) {
+            Some(bucket) => bucket.refill(config, now) >= 1.0,
+            // A source that we haven't seen has a full bucket.
+            None => config.burst >= 1.0,
+        }
+    }
+
+    /// Take a token from the source.
+    fn take_token(&mut self, source: Cidr, now: Instant) {
+        if now.saturating_duration_since(self.last_cleanup) >= CLEANUP_INTERVAL {
+            self.cleanup(now);
+            self.last_cleanup = now;
+        }
+
+        if !self.buckets.contains_key(&source) {
+            // Forgetting a bucket gives its source a full one, but it's better than running out
+            // of memory.
+            if self.buckets.len() >= MAX_BUCKETS {
+                if let Some(oldest) = self.order.pop_front() {
+                    self.buckets.remove(&oldest);
+                }
+            }
+            self.buckets.insert(
+                source,
+                TokenBucket {
+                    tokens: self.config.burst,
+                    last_refill: now,
+                },
+            );
+            self.order.push_back(source);
+        }
+
+        let bucket = self.buckets.get_mut(&source).unwrap();
+        bucket.refill(&self.config, now);
+        bucket.tokens -= 1.0;
+    }
+
+    /// Drop all the full buckets. They are the same as the buckets that don't exist.
+    fn cleanup(&mut self, now: Instant) {
+        let config = &self.config;
+        self.buckets
+            .retain(|_, bucket| bucket.refill(config, now) < config.burst);
+        let buckets = &self.buckets;
+        self.order.retain(|source| buckets.contains_key(source));
+    }
+}
+
+/// Token-bucket rate limiter for new connections, by client address and by client network.
+pub(super) struct RateLimiter {
+    per_ip: Option<Buckets>,
+    per_prefix: Option<Buckets>,
+    ipv4_prefix_len: u8,
+    ipv6_prefix_len: u8,
+}
+
+impl RateLimiter {
+    pub(super) fn new(config: &RateLimitConfig) -> RateLimiter {
+        RateLimiter {
+            per_ip: config.per_ip.map(Buckets::new),
+            per_prefix: config.per_prefix.map(Buckets::new),
+            ipv4_prefix_len: config.ipv4_prefix_len,
+            ipv6_prefix_len: config.ipv6_prefix_len,
+        }
+    }
+
+    /// Take a token for a new connection from the client address and from its network.
+    ///
+    /// # Errors
+    ///
+    /// Return the reason if either of them has run out of tokens. In that case, no token is taken.
+    ///
+    pub(super) fn admit(&mut self, addr: &IpAddr) -> Result<(), Rejection> {
+        let now = Instant::now();
+        let addr = canonical_ip(*addr);
+        let ip = Cidr::containing(&addr, 128);
+        let prefix_len = match addr {
+            IpAddr::V4(_) => self.ipv4_prefix_len,
+            IpAddr::V6(_) => self.ipv6_prefix_len,
+        };
+        let prefix = Cidr::containing(&addr, prefix_len);
+
+        if let Some(buckets) = &mut self.per_ip {
+            if !buckets.has_token(ip, now) {
+                return Err(Rejection::IpRate);
+            }
+        }
+        if let Some(buckets) = &mut self.per_prefix {
+            if !buckets.has_token(prefix, now) {
+                return Err(Rejection::PrefixRate);
+            }
+        }
+
+        if let Some(buckets) = &mut self.per_ip {
+            buckets.take_token(ip, now);
+        }
+        if let Some(buckets) = &mut self.per_prefix {
+            buckets.take_token(prefix, now);
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_admit() {
+        let config = RateLimitConfig {
+            per_ip: Some(TokenBucketConfig {
+                rate: 0.001,
+                burst: 2.0,
+            }),
+            per_prefix: Some(TokenBucketConfig {
+                rate: 0.001,
+                burst: 3.0,
+            }),
+            ipv4_prefix_len: 24,
+            ipv6_prefix_len: 48,
+        };
+        let mut limiter = RateLimiter::new(&config);
+
+        let first = "192.0.2.1".parse().unwrap();
+        let second = "192.0.2.2".parse().unwrap();
+        let other = "198.51.100.1".parse().unwrap();
+
+        assert_eq!(limiter.admit(&first), Ok(()));
+        assert_eq!(limiter.admit(&first), Ok(()));
+        assert_eq!(limiter.admit(&first), Err(Rejection::IpRate));
+        assert_eq!(limiter.admit(&second), Ok(()));
+        assert_eq!(limiter.admit(&second), Err(Rejection::PrefixRate));
+        assert_eq!(limiter.admit(&other), Ok(()));
+    }
+
+    #[test]
+    fn test_bucket_limit() {
+        let config = TokenBucketConfig {
+            rate: 0.001,
+            burst: 1.0,
+        };
+        let mut buckets = Buckets::new(config);
+        let now = buckets.last_cleanup;
+        let source = |index: usize| {
+            let addr = std::net::Ipv6Addr::from(0x2001_0db8_u128 << 96 | index as u128);
+            Cidr::containing(&IpAddr::V6(addr), 128)
+        };
+
+        // None of the buckets are full, so only the limit keeps the number of buckets down.
+        for index in 0..=MAX_BUCKETS {
+            assert!(buckets.has_token(source(index), now));
+            buckets.take_token(source(index), now);
+        }
+        assert_eq!(buckets.buckets.len(), MAX_BUCKETS);
+        assert_eq!(buckets.order.len(), MAX_BUCKETS);
+        assert!(buckets.has_token(source(0), now));
+        assert!(!buckets.has_token(source(1), now));
+        assert!(!buckets.has_token(source(MAX_BUCKETS), now));
+
+        // The buckets refilled since then are dropped at the next cleanup.
+        let later = now + Duration::from_secs(1000);
+        buckets.take_token(source(0), later);
+        assert_eq!(buckets.buckets.len(), 1);
+        assert_eq!(buckets.order, vec![source(0)]);
+    }
+}
@@ -0,0 +1,155 @@
+// This file is part of cfnts.
+// Copyright (c) 2019, Cloudflare. All rights reserved.
+// See LICENSE for licensing information.
+
+//! TLS session tickets encrypted with keys from the key rotator.
+//!
+//! Every NTS-KE server of a fleet shares the keys of the rotator through Memcached, so a ticket
+//! issued by one server can be decrypted by any other. The ticket key of a period is derived from
+//! the rotator key of that period, so the cookie keys themselves are never used for tickets.
+
+use lazy_static::lazy_static;
+
+use prometheus::{__register_counter_vec, opts, register_int_counter_vec, IntCounterVec};
+
+use rand::Rng;
+
+use std::sync::{Arc, RwLock};
+
+use crate::crypto::{hmac_sha256, Aead, AeadAesSivCmac256, HmacSha256Tag};
+use crate::key_rotator::{KeyId, KeyRotator};
+
+/// The label of the ticket key derivation from a rotator key.
+const TICKET_KEY_LABEL: &[u8] = b"cfnts tls session ticket key";
+
+/// The length of the nonce of a ticket.
+const NONCE_LEN: usize = 16;
+
+/// The length of the key id at the beginning of a ticket.
+const KEY_ID_LEN: usize = 4;
+
+lazy_static! {
+    static ref TICKET_COUNTER: IntCounterVec = register_int_counter_vec!(
+        "nts_ke_tls_session_tickets_total",
+        "Number of TLS session tickets issued and presented by result",
+        &["result"]
+    )
+    .unwrap();
+}
+
+/// Encrypt a ticket with the rotator key `key` of `key_id`. The ticket is the key id, the nonce
+/// and the ciphertext, like a cookie.
+fn seal_ticket(key_id: KeyId, key: &HmacSha256Tag, plaintext: &[u8]) -> Vec<u8> {
+    let mut nonce = [0; NONCE_LEN];
+    rand::thread_rng().fill(&mut nonce);
+
+    let ticket_key = hmac_sha256(key, TICKET_KEY_LABEL);
+    let mut aead = AeadAesSivCmac256::new(&ticket_key);
+    // The key id is authenticated, so a ticket cannot be moved to another key.
+    let mut ciphertext = aead.seal(&nonce, &key_id.to_be_bytes(), plaintext);
+
+    let mut ticket = Vec::with_capacity(KEY_ID_LEN + NONCE_LEN + ciphertext.len());
+    ticket.extend(&key_id.to_be_bytes());
+    ticket.extend(&nonce);
+    ticket.append(&mut ciphertext);
+    ticket
+}
+
+/// Return the key id of a ticket and decrypt it with `key`, which is the rotator key of that key
+/// id. `key` is `None` if the key is not known anymore.
+///
+/// The result is the counter label and the plaintext, if the ticket is authentic.
+fn open_ticket<'a, F>(ticket: &[u8], key: F) -> (&'static str, Option<Vec<u8>>)
+where
+    F: FnOnce(KeyId) -> Option<&'a HmacSha256Tag>,
+{
+    if ticket.len() < KEY_ID_LEN + NONCE_LEN {
+        return ("invalid", None);
+    }
+    let (key_id, rest) = ticket.split_at(KEY_ID_LEN);
+    let (nonce, ciphertext) = rest.split_at(NONCE_LEN);
+
+    let mut key_id_bytes = [0; KEY_ID_LEN];
+    key_id_bytes.copy_from_slice(key_id);
+
+    let key = match key(KeyId::from_be_bytes(key_id_bytes)) {
+        Some(key) => key,
+        // The ticket is older than the keys that the rotator keeps, or comes from somewhere else.
+        None => return ("unknown_key", None),
+    };
+
+    let ticket_key = hmac_sha256(key, TICKET_KEY_LABEL);
+    let mut aead = AeadAesSivCmac256::new(&ticket_key);
+    match aead.open(nonce, key_id, ciphertext) {
+        Ok(plaintext) => ("resumed", Some(plaintext)),
+        Err(_) => ("invalid", None),
+    }
+}
+
+/// Producer of TLS session tickets with the keys of the rotator.
+pub(super) struct RotatingTicketer {
+    /// The key rotator shared with the cookies.
+    rotator: Arc<RwLock<KeyRotator>>,
+
+    /// How long clients may use a ticket in seconds.
+    lifetime: u32,
+}
+
+impl RotatingTicketer {
+    /// Create a ticketer that issues tickets valid for `lifetime` seconds.
+    pub(super) fn new(rotator: Arc<RwLock<KeyRotator>>, lifetime: u32) -> RotatingTicketer {
+        RotatingTicketer { rotator, lifetime }
+    }
+}
+
+impl rustls::ProducesTickets for RotatingTicketer {
+    fn enabled(&self) -> bool {
+        true
+    }
+
+    fn get_lifetime(&self) -> u32 {
+        self.lifetime
+    }
+
+    fn encrypt(&self, plaintext: &[u8]) -> Option<Vec<u8>> {
+        let rotator = self.rotator.read().unwrap();
+        let (key_id, key) = rotator.latest_key_value();
+        TICKET_COUNTER.with_label_values(&["issued"]).inc();
+        Some(seal_ticket(key_id, key, plaintext))
+    }
+
+    fn decrypt(&self, ticket: &[u8]) -> Option<Vec<u8>> {
+        let rotator = self.rotator.read().unwrap();
+        let (result, plaintext) = open_ticket(ticket, |key_id| rotator.get(key_id));
+        TICKET_COUNTER.with_label_values(&[result]).inc();
+        plaintext
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_ticket_round_trip() {
+        let key_id = KeyId::new(42);
+        let key = [7; 32];
+        let ticket = seal_ticket(key_id, &key, b"session state");
+
+        let known = |id| if id == key_id { Some(&key) } else { None };
+        assert_eq!(
+            open_ticket(&ticket, known),
+            ("resumed", Some(b"session state".to_vec()))
+        );
+
+        // Another server of the fleet that has already dropped the key.
+        assert_eq!(open_ticket(&ticket, |_| None), ("unknown_key", None));
+
+        // The key id is authenticated.
+        let mut moved = ticket.clone();
+        moved[3] ^= 1;
+        assert_eq!(open_ticket(&moved, |_| Some(&key)), ("invalid", None));
+
+        assert_eq!(open_ticket(&ticket[..10], known), ("invalid", None));
+    }
+}
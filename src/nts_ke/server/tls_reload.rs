@@ -0,0 +1,174 @@
+// This file is part of cfnts.
+// Copyright (c) 2019, Cloudflare. All rights reserved.
+// See LICENSE for licensing information.
+
+//! Reloading of TLS certificates while the NTS-KE server is running.
+//!
+//! Stapled OCSP responses are reloaded with the certificates, and dropped when they expire. The
+//! expiry of the certificates themselves is monitored here as well.
+
+use lazy_static::lazy_static;
+
+use prometheus::{__register_counter_vec, opts, register_int_counter_vec, IntCounterVec};
+
+use slog::{error, info, warn};
+
+use std::fs;
+use std::sync::Arc;
+use std::thread;
+use std::time::{Duration, SystemTime};
+
+use crate::signal;
+
+use super::cert_expiry::CertExpiryMonitor;
+use super::config::{ClientAuthConfig, TlsIdentity};
+use super::ke_server::{build_tls_server_config, KeServerState};
+use super::ticketer::RotatingTicketer;
+
+/// How often we check for SIGHUP, for changes of the certificate and key files, and for expired
+/// OCSP responses.
+const CHECK_INTERVAL: Duration = Duration::from_secs(1);
+
+lazy_static! {
+    static ref RELOAD_COUNTER: IntCounterVec = register_int_counter_vec!(
+        "nts_ke_tls_reload_total",
+        "Number of TLS certificate reloads by result",
+        &["result"]
+    )
+    .unwrap();
+}
+
+/// Return the modification times of all the certificate, key and OCSP response files, and the
+/// client CA file. A file that cannot be read has no modification time.
+fn modification_times(
+    identities: &[TlsIdentity],
+    client_auth: &ClientAuthConfig,
+) -> Vec<Option<SystemTime>> {
+    identities
+        .iter()
+        .flat_map(|identity| {
+            vec![&identity.cert_file, &identity.key_file]
+                .into_iter()
+                .chain(identity.ocsp_file.iter())
+        })
+        .chain(client_auth.ca_file.iter())
+        .map(|filename| {
+            fs::metadata(filename)
+                .and_then(|metadata| metadata.modified())
+                .ok()
+        })
+        .collect()
+}
+
+/// Read all the certificate, key and OCSP response files again and build a new TLS server
+/// configuration. Return it with the reloaded identities.
+fn reload(
+    identities: &[TlsIdentity],
+    client_auth: &ClientAuthConfig,
+    ticketer: Option<&Arc<RotatingTicketer>>,
+) -> Result<(rustls::ServerConfig, Vec<TlsIdentity>), String> {
+    let mut reloaded = Vec::new();
+    for identity in identities {
+        let identity = TlsIdentity::load(
+            identity.names.clone(),
+            identity.cert_file.clone(),
+            identity.key_file.clone(),
+            identity.ocsp_file.clone(),
+        )
+        .map_err(|error| error.to_string())?;
+        reloaded.push(identity);
+    }
+    let tls_server_config = build_tls_server_config(&reloaded, client_auth, ticketer)
+        .map_err(|error| error.to_string())?;
+    Ok((tls_server_config, reloaded))
+}
+
+/// Warn about the OCSP responses that are expired, which are not stapled, and return when the
+/// first of the others expires.
+fn check_ocsp_expiry(logger: &slog::Logger, identities: &[TlsIdentity]) -> Option<SystemTime> {
+    let now = SystemTime::now();
+    let mut first_expiry = None;
+    for identity in identities {
+        let (response, ocsp_file) = match (&identity.ocsp, &identity.ocsp_file) {
+            (Some(response), Some(ocsp_file)) => (response, ocsp_file),
+            _ => continue,
+        };
+        if response.is_expired(now) {
+            warn!(
+                logger,
+                "the OCSP response in {} is expired, so it's not stapled", ocsp_file
+            );
+        } else if let Some(next_update) = response.next_update {
+            first_expiry =
+                Some(first_expiry.map_or(next_update, |first: SystemTime| first.min(next_update)));
+        }
+    }
+    first_expiry
+}
+
+/// Create a new thread that reloads the certificates on SIGHUP or when one of the files changes,
+/// stops stapling OCSP responses when they expire, and monitors the expiry of the certificates.
+///
+/// New sessions use the reloaded certificates. If they cannot be reloaded, the old ones are kept.
+pub(super) fn periodic_tls_reload(state: Arc<KeServerState>) {
+    let logger = state
+        .config
+        .logger()
+        .new(slog::o!("component" => "tls_reload"));
+    // The identities that the current TLS configuration is built from.
+    let mut identities = state.config.tls_identities.clone();
+    let client_auth = &state.config.tls_client_auth;
+
+    let mut last_times = modification_times(&identities, client_auth);
+    let mut last_sighup = signal::sighup_count();
+    let mut ocsp_expiry = check_ocsp_expiry(&logger, &identities);
+    let mut expiry_monitor = CertExpiryMonitor::new();
+    expiry_monitor.check(&logger, &identities);
+
+    thread::spawn(move || loop {
+        thread::sleep(CHECK_INTERVAL);
+        expiry_monitor.check(&logger, &identities);
+
+        let client_auth = &state.config.tls_client_auth;
+        let times = modification_times(&identities, client_auth);
+        let sighup_count = signal::sighup_count();
+        let sighup = sighup_count != last_sighup;
+        last_sighup = sighup_count;
+        if !sighup && times == last_times {
+            // Without a new response, the expired one is dropped from the current identities.
+            if matches!(ocsp_expiry, Some(expiry) if expiry <= SystemTime::now()) {
+                ocsp_expiry = check_ocsp_expiry(&logger, &identities);
+                match build_tls_server_config(&identities, client_auth, state.ticketer.as_ref()) {
+                    Ok(tls_server_config) => state.set_tls_server_config(tls_server_config),
+                    Err(err) => error!(logger, "cannot drop the expired OCSP response: {}", err),
+                }
+            }
+            continue;
+        }
+        // If a certificate and its key are replaced one after another, the first reload may fail
+        // because they don't match. The next change triggers another reload.
+        last_times = times;
+
+        info!(
+            logger,
+            "reloading TLS certificates";
+            "reason" => if sighup { "sighup" } else { "file change" }
+        );
+        match reload(&identities, client_auth, state.ticketer.as_ref()) {
+            Ok((tls_server_config, reloaded)) => {
+                state.set_tls_server_config(tls_server_config);
+                identities = reloaded;
+                ocsp_expiry = check_ocsp_expiry(&logger, &identities);
+                RELOAD_COUNTER.with_label_values(&["success"]).inc();
+                info!(logger, "reloaded TLS certificates");
+            }
+            Err(err) => {
+                RELOAD_COUNTER.with_label_values(&["failure"]).inc();
+                error!(
+                    logger,
+                    "cannot reload TLS certificates, keeping the old ones: {}", err
+                );
+            }
+        }
+    });
+}
@@ -0,0 +1,294 @@
+// This file is part of cfnts.
+// Copyright (c) 2019, Cloudflare. All rights reserved.
+// See LICENSE for licensing information.
+
+//! Loading of TLS private keys.
+//!
+//! rustls can sign with PKCS#1 RSA keys and PKCS#8 RSA or EC keys, but it only reads the PEM
+//! files of the former two, and it cannot use SEC1 EC keys at all. We read PKCS#1, SEC1 and
+//! PKCS#8 keys, either in PEM or in DER, and wrap SEC1 keys in PKCS#8 before handing them over.
+
+use rustls::sign;
+use rustls::{Certificate, PrivateKey, SignatureScheme};
+
+use std::fmt;
+use std::fs;
+use std::io;
+
+use crate::der::{
+    expect_element, read_element, write_element, TAG_INTEGER, TAG_OCTET_STRING, TAG_OID,
+    TAG_SEQUENCE,
+};
+
+/// The object identifier of RSA keys, 1.2.840.113549.1.1.1.
+const OID_RSA_ENCRYPTION: &[u8] = &[0x2a, 0x86, 0x48, 0x86, 0xf7, 0x0d, 0x01, 0x01, 0x01];
+
+/// The object identifier of EC keys, 1.2.840.10045.2.1.
+const OID_EC_PUBLIC_KEY: &[u8] = &[0x2a, 0x86, 0x48, 0xce, 0x3d, 0x02, 0x01];
+
+/// The context-specific tag of the curve parameters of a SEC1 key.
+const TAG_EC_PARAMETERS: u8 = 0xa0;
+
+/// The message that is signed to check that a key matches a certificate.
+const CHECK_MESSAGE: &[u8] = b"cfnts private key check";
+
+/// The encoding and the algorithm of a private key.
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+pub enum KeyType {
+    /// RSAPrivateKey from RFC 8017 appendix A.1.2.
+    Pkcs1Rsa,
+    /// ECPrivateKey from RFC 5915 section 3.
+    Sec1Ec,
+    /// PrivateKeyInfo from RFC 5208 section 5 with an RSA key.
+    Pkcs8Rsa,
+    /// PrivateKeyInfo from RFC 5208 section 5 with an EC key.
+    Pkcs8Ec,
+    /// PrivateKeyInfo from RFC 5208 section 5 with another algorithm.
+    Pkcs8Other,
+}
+
+impl fmt::Display for KeyType {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        let name = match self {
+            KeyType::Pkcs1Rsa => "PKCS#1 RSA",
+            KeyType::Sec1Ec => "SEC1 EC",
+            KeyType::Pkcs8Rsa => "PKCS#8 RSA",
+            KeyType::Pkcs8Ec => "PKCS#8 EC",
+            KeyType::Pkcs8Other => "PKCS#8",
+        };
+        write!(f, "{}", name)
+    }
+}
+
+/// Tell the type of a DER-encoded private key from its structure, or return `None` if it's not a
+/// private key that we know.
+fn key_type(der: &[u8]) -> Option<KeyType> {
+    let (key, _) = expect_element(der, TAG_SEQUENCE)?;
+    let (version, key) = expect_element(key, TAG_INTEGER)?;
+
+    match (version, read_element(key)?) {
+        // PKCS#8 version 1 and 2 both start with the algorithm identifier.
+        (_, (TAG_SEQUENCE, algorithm, _)) => {
+            let (oid, _) = expect_element(algorithm, TAG_OID)?;
+            Some(match oid {
+                OID_RSA_ENCRYPTION => KeyType::Pkcs8Rsa,
+                OID_EC_PUBLIC_KEY => KeyType::Pkcs8Ec,
+                _ => KeyType::Pkcs8Other,
+            })
+        }
+        // The modulus follows the version of an RSA key.
+        ([0], (TAG_INTEGER, _, _)) => Some(KeyType::Pkcs1Rsa),
+        // The private key follows the version of an EC key.
+        ([1], (TAG_OCTET_STRING, _, _)) => Some(KeyType::Sec1Ec),
+        _ => None,
+    }
+}
+
+/// Wrap a SEC1 EC key in PKCS#8, or return `None` if it doesn't name its curve.
+///
+/// The SEC1 key is kept as it is inside. ring accepts its curve parameters and requires its
+/// public key, which OpenSSL always writes.
+fn sec1_to_pkcs8(der: &[u8]) -> Option<Vec<u8>> {
+    let (key, rest) = expect_element(der, TAG_SEQUENCE)?;
+    let sec1 = &der[..der.len() - rest.len()];
+
+    let (_version, key) = expect_element(key, TAG_INTEGER)?;
+    let (_private_key, mut fields) = expect_element(key, TAG_OCTET_STRING)?;
+
+    // The optional fields are tagged, so we have to look for the parameters.
+    let curve = loop {
+        let (tag, content, rest) = read_element(fields)?;
+        if tag == TAG_EC_PARAMETERS {
+            let (curve, _) = expect_element(content, TAG_OID)?;
+            break curve;
+        }
+        fields = rest;
+    };
+
+    let mut algorithm = write_element(TAG_OID, OID_EC_PUBLIC_KEY);
+    algorithm.extend(write_element(TAG_OID, curve));
+
+    let mut info = write_element(TAG_INTEGER, &[0]);
+    info.extend(write_element(TAG_SEQUENCE, &algorithm));
+    info.extend(write_element(TAG_OCTET_STRING, sec1));
+    Some(write_element(TAG_SEQUENCE, &info))
+}
+
+/// Return the label and the decoded content of the first private key block of a PEM file. Other
+/// blocks, like the EC parameters that OpenSSL writes before EC keys, are skipped.
+fn pem_private_key(text: &str, filename: &str) -> Result<(String, Vec<u8>), io::Error> {
+    let invalid = |message: String| io::Error::new(io::ErrorKind::InvalidData, message);
+
+    let mut lines = text.lines().map(str::trim);
+    while let Some(line) = lines.next() {
+        let label = match line
+            .strip_prefix("-----BEGIN ")
+            .and_then(|line| line.strip_suffix("-----"))
+        {
+            Some(label) if label.ends_with("PRIVATE KEY") => label,
+            _ => continue,
+        };
+        if label == "ENCRYPTED PRIVATE KEY" {
+            return Err(invalid(format!(
+                "the private key in {} is encrypted, which is not supported",
+                filename
+            )));
+        }
+
+        let end = format!("-----END {}-----", label);
+        let base64: String = lines.by_ref().take_while(|line| *line != end).collect();
+        let der = base64::decode(&base64).map_err(|error| {
+            invalid(format!(
+                "cannot decode the {} block in {}: {}",
+                label, filename, error
+            ))
+        })?;
+        return Ok((String::from(label), der));
+    }
+
+    Err(invalid(format!(
+        "no PKCS#1, SEC1 or PKCS#8 private key in {}",
+        filename
+    )))
+}
+
+/// Read a TLS private key from a PEM or DER file. If there are many keys in a PEM file, the
+/// first one is used.
+///
+/// Return the key in a form that rustls accepts, and its original type.
+///
+/// # Errors
+///
+/// There will be an error if we cannot read the file, the key is not a PKCS#1, SEC1 or PKCS#8
+/// key, or rustls cannot sign with it.
+///
+pub fn read_private_key(filename: &str) -> Result<(PrivateKey, KeyType), io::Error> {
+    let invalid = |message: String| io::Error::new(io::ErrorKind::InvalidData, message);
+
+    let content = fs::read(filename).map_err(|error| {
+        io::Error::new(error.kind(), format!("cannot read {}: {}", filename, error))
+    })?;
+    let (der, label) = match std::str::from_utf8(&content) {
+        Ok(text) if text.contains("-----BEGIN ") => {
+            let (label, der) = pem_private_key(text, filename)?;
+            (der, Some(label))
+        }
+        // Anything else is taken as DER.
+        _ => (content, None),
+    };
+
+    let key_type = key_type(&der).ok_or_else(|| {
+        invalid(format!(
+            "the private key in {} is not a PKCS#1, SEC1 or PKCS#8 key",
+            filename
+        ))
+    })?;
+
+    let expected_label = match key_type {
+        KeyType::Pkcs1Rsa => "RSA PRIVATE KEY",
+        KeyType::Sec1Ec => "EC PRIVATE KEY",
+        _ => "PRIVATE KEY",
+    };
+    if let Some(label) = label {
+        if label != expected_label {
+            return Err(invalid(format!(
+                "the {} block in {} holds a {} key",
+                label, filename, key_type
+            )));
+        }
+    }
+
+    let der = if key_type == KeyType::Sec1Ec {
+        sec1_to_pkcs8(&der).ok_or_else(|| {
+            invalid(format!(
+                "the SEC1 EC private key in {} doesn't name its curve",
+                filename
+            ))
+        })?
+    } else {
+        der
+    };
+
+    let key = PrivateKey(der);
+    if sign::any_supported_type(&key).is_err() {
+        return Err(invalid(format!(
+            "the {} private key in {} is malformed or uses an unsupported algorithm or curve",
+            key_type, filename
+        )));
+    }
+    Ok((key, key_type))
+}
+
+/// Check that `key` is the private key of the public key in `cert`, by signing a message with
+/// the key and verifying the signature with the certificate.
+///
+/// # Errors
+///
+/// Return the reason if the key doesn't match the certificate, or one of them is not usable.
+///
+pub fn check_key_matches(cert: &Certificate, key: &PrivateKey) -> Result<(), String> {
+    let signing_key = sign::any_supported_type(key)
+        .map_err(|()| String::from("the private key is not usable"))?;
+    // Offer one scheme for each key type that rustls supports.
+    let signer = signing_key
+        .choose_scheme(&[
+            SignatureScheme::RSA_PSS_SHA256,
+            SignatureScheme::ECDSA_NISTP256_SHA256,
+            SignatureScheme::ECDSA_NISTP384_SHA384,
+        ])
+        .ok_or_else(|| String::from("the private key cannot sign for TLS 1.3"))?;
+
+    let algorithm = match signer.get_scheme() {
+        SignatureScheme::RSA_PSS_SHA256 => &webpki::RSA_PSS_2048_8192_SHA256_LEGACY_KEY,
+        SignatureScheme::ECDSA_NISTP256_SHA256 => &webpki::ECDSA_P256_SHA256,
+        SignatureScheme::ECDSA_NISTP384_SHA384 => &webpki::ECDSA_P384_SHA384,
+        scheme => return Err(format!("unexpected signature scheme {:?}", scheme)),
+    };
+    let signature = signer
+        .sign(CHECK_MESSAGE)
+        .map_err(|error| format!("cannot sign with the private key: {}", error))?;
+
+    let cert = webpki::EndEntityCert::from(&cert.0)
+        .map_err(|error| format!("cannot parse the X.509 v3 certificate: {:?}", error))?;
+    cert.verify_signature(algorithm, CHECK_MESSAGE, &signature)
+        .map_err(|_| String::from("the certificate has a different public key"))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use rustls::internal::pemfile;
+
+    use std::fs::File;
+    use std::io::BufReader;
+
+    fn read_cert(filename: &str) -> Certificate {
+        let file = File::open(filename).unwrap();
+        pemfile::certs(&mut BufReader::new(file)).unwrap().remove(0)
+    }
+
+    #[test]
+    fn test_read_private_key() {
+        let tls_cert = read_cert("tests/tls.pem");
+        let rsa_cert = read_cert("tests/rsa.pem");
+
+        for (filename, expected_type, cert) in &[
+            ("tests/tls-key.pem", KeyType::Sec1Ec, &tls_cert),
+            ("tests/tls-key.der", KeyType::Sec1Ec, &tls_cert),
+            ("tests/tls-pkcs8.pem", KeyType::Pkcs8Ec, &tls_cert),
+            ("tests/ca-key.pem", KeyType::Pkcs8Rsa, &rsa_cert),
+            ("tests/ca-key-pkcs1.pem", KeyType::Pkcs1Rsa, &rsa_cert),
+        ] {
+            let (key, key_type) = read_private_key(filename).unwrap();
+            assert_eq!(key_type, *expected_type, "{}", filename);
+            assert_eq!(check_key_matches(cert, &key), Ok(()), "{}", filename);
+        }
+
+        let (key, _) = read_private_key("tests/ca-key-pkcs1.pem").unwrap();
+        assert!(check_key_matches(&tls_cert, &key).is_err());
+
+        assert!(read_private_key("tests/tls.pem").is_err());
+        assert!(read_private_key("tests/cookie.key").is_err());
+    }
+}
@@ -0,0 +1,87 @@
+// This file is part of cfnts.
+// Copyright (c) 2019, Cloudflare. All rights reserved.
+// See LICENSE for licensing information.
+
+//! Process signal handling.
+//!
+//! Signal handlers only set flags and counters. Threads that care about a signal poll them.
+
+use std::io;
+use std::sync::atomic::{AtomicBool, AtomicUsize, Ordering};
+
+/// The number of SIGHUPs received. Each thread that reloads something on SIGHUP compares it with
+/// the number it has last seen, so that they don't take the signal from each other.
+static SIGHUP_COUNT: AtomicUsize = AtomicUsize::new(0);
+
+/// Set when SIGTERM or SIGINT is received. It's never cleared.
+static SHUTDOWN_REQUESTED: AtomicBool = AtomicBool::new(false);
+
+extern "C" fn handle_sighup(_: libc::c_int) {
+    // Only async-signal-safe operations are allowed here. Updating a lock-free atomic is one of
+    // them.
+    SIGHUP_COUNT.fetch_add(1, Ordering::SeqCst);
+}
+
+extern "C" fn handle_shutdown(_: libc::c_int) {
+    // Only async-signal-safe operations are allowed here. Storing an atomic is one of them.
+    SHUTDOWN_REQUESTED.store(true, Ordering::SeqCst);
+}
+
+/// Install `handler` for `signum`.
+fn install_handler(
+    signum: libc::c_int,
+    handler: extern "C" fn(libc::c_int),
+) -> Result<(), io::Error> {
+    // It's safe because the handlers only touch atomics.
+    let previous = unsafe { libc::signal(signum, handler as libc::sighandler_t) };
+    if previous == libc::SIG_ERR {
+        return Err(io::Error::last_os_error());
+    }
+    Ok(())
+}
+
+/// Install a handler that records SIGHUP instead of terminating the process.
+///
+/// # Errors
+///
+/// There will be an error if the handler cannot be installed.
+///
+pub fn install_sighup_handler() -> Result<(), io::Error> {
+    install_handler(libc::SIGHUP, handle_sighup)
+}
+
+/// Install handlers that record SIGTERM and SIGINT instead of terminating the process, so that
+/// servers can shut down gracefully.
+///
+/// # Errors
+///
+/// There will be an error if the handlers cannot be installed.
+///
+pub fn install_shutdown_handler() -> Result<(), io::Error> {
+    install_handler(libc::SIGTERM, handle_shutdown)?;
+    install_handler(libc::SIGINT, handle_shutdown)
+}
+
+/// Return the number of SIGHUPs received so far.
+pub fn sighup_count() -> usize {
+    SIGHUP_COUNT.load(Ordering::SeqCst)
+}
+
+/// Return true if SIGTERM or SIGINT has been received.
+pub fn shutdown_requested() -> bool {
+    SHUTDOWN_REQUESTED.load(Ordering::SeqCst)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_sighup() {
+        install_sighup_handler().unwrap();
+        let count = sighup_count();
+
+        unsafe { libc::raise(libc::SIGHUP) };
+        assert_eq!(sighup_count(), count + 1);
+    }
+}
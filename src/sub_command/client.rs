@@ -10,17 +10,20 @@ use std::fs;
 use std::io::BufReader;
 use std::process;
 
-use rustls::{internal::pemfile::certs, Certificate};
+use rustls::{internal::pemfile::certs, Certificate, PrivateKey};
 
 use crate::error::WrapError;
 use crate::ntp::client::run_nts_ntp_client;
 use crate::nts_ke::client::run_nts_ke_client;
+use crate::private_key;
 
 #[derive(Debug)]
 pub struct ClientConfig {
     pub host: String,
     pub port: Option<String>,
     pub trusted_cert: Option<Certificate>,
+    /// The certificate chain and the private key to authenticate the client with.
+    pub client_identity: Option<(Vec<Certificate>, PrivateKey)>,
     pub use_ipv4: Option<bool>,
 }
 
@@ -30,6 +33,29 @@ pub fn load_tls_certs(path: String) -> Result<Vec<Certificate>, config::ConfigEr
     })
 }
 
+/// Load the certificate chain and the private key that authenticate the client. The private key
+/// may be a PKCS#1, SEC1 or PKCS#8 key, in PEM or DER, and must match the first certificate.
+pub fn load_client_identity(
+    cert_file: String,
+    key_file: String,
+) -> Result<(Vec<Certificate>, PrivateKey), config::ConfigError> {
+    let certs = load_tls_certs(cert_file.clone())?;
+    if certs.is_empty() {
+        return Err(config::ConfigError::Message(format!(
+            "no certificate in {}",
+            cert_file
+        )));
+    }
+    let (key, key_type) = private_key::read_private_key(&key_file).wrap_err()?;
+    private_key::check_key_matches(&certs[0], &key).map_err(|reason| {
+        config::ConfigError::Message(format!(
+            "the {} private key in {} doesn't match the certificate in {}: {}",
+            key_type, key_file, cert_file, reason
+        ))
+    })?;
+    Ok((certs, key))
+}
+
 /// The entry point of `client`.
 pub fn run(matches: &clap::ArgMatches<'_>) {
     // This should return the clone of `logger` in the main function.
@@ -62,10 +88,26 @@ pub fn run(matches: &clap::ArgMatches<'_>) {
         }
     }
 
+    // clap makes sure that both or neither of them are given.
+    let mut client_identity = None;
+    if let (Some(cert_file), Some(key_file)) = (
+        matches.value_of("client-cert"),
+        matches.value_of("client-key"),
+    ) {
+        match load_client_identity(String::from(cert_file), String::from(key_file)) {
+            Ok(identity) => client_identity = Some(identity),
+            Err(err) => {
+                eprintln!("cannot load the client certificate: {}", err);
+                process::exit(1)
+            }
+        }
+    }
+
     let client_config = ClientConfig {
         host,
         port,
         trusted_cert,
+        client_identity,
         use_ipv4,
     };
 
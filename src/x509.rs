@@ -0,0 +1,120 @@
+// This file is part of cfnts.
+// Copyright (c) 2019, Cloudflare. All rights reserved.
+// See LICENSE for licensing information.
+
+//! Minimal reading of X.509 certificates.
+//!
+//! rustls and webpki verify certificates but don't expose their fields, so we walk the DER
+//! encoding ourselves for the few fields that we need. See RFC 5280 section 4.1 for the structure.
+
+use std::time::SystemTime;
+
+use crate::der::{
+    expect_element, parse_time, read_element, TAG_INTEGER, TAG_OID, TAG_SEQUENCE, TAG_SET,
+};
+
+/// The context-specific tag of the version of a certificate.
+const TAG_VERSION: u8 = 0xa0;
+
+/// The object identifier of the common name attribute, 2.5.4.3.
+const OID_COMMON_NAME: &[u8] = &[0x55, 0x04, 0x03];
+
+/// Return the fields of the TBSCertificate of a certificate, starting from the serial number.
+fn tbs_from_serial(cert: &[u8]) -> Option<&[u8]> {
+    let (cert, _) = expect_element(cert, TAG_SEQUENCE)?;
+    let (tbs, _) = expect_element(cert, TAG_SEQUENCE)?;
+
+    // The version is optional and defaults to v1.
+    match read_element(tbs)? {
+        (TAG_VERSION, _, rest) => Some(rest),
+        _ => Some(tbs),
+    }
+}
+
+/// Return the fields of the TBSCertificate of a certificate, starting from the issuer.
+fn tbs_from_issuer(cert: &[u8]) -> Option<&[u8]> {
+    let (_serial, tbs) = expect_element(tbs_from_serial(cert)?, TAG_INTEGER)?;
+    let (_signature, tbs) = expect_element(tbs, TAG_SEQUENCE)?;
+    Some(tbs)
+}
+
+/// Return the content of the serial number of a DER-encoded certificate, or `None` if the
+/// certificate is malformed.
+pub fn serial_number(cert: &[u8]) -> Option<&[u8]> {
+    let (serial, _) = expect_element(tbs_from_serial(cert)?, TAG_INTEGER)?;
+    Some(serial)
+}
+
+/// Return the end of the validity period of a DER-encoded certificate, or `None` if the
+/// certificate is malformed.
+pub fn not_after(cert: &[u8]) -> Option<SystemTime> {
+    let tbs = tbs_from_issuer(cert)?;
+    let (_issuer, tbs) = expect_element(tbs, TAG_SEQUENCE)?;
+    let (validity, _) = expect_element(tbs, TAG_SEQUENCE)?;
+    let (_not_before, _, validity) = read_element(validity)?;
+    let (tag, not_after, _) = read_element(validity)?;
+    parse_time(tag, not_after)
+}
+
+/// Return the common name in the subject of a DER-encoded certificate, or `None` if there is no
+/// common name or the certificate is malformed.
+pub fn subject_common_name(cert: &[u8]) -> Option<String> {
+    let tbs = tbs_from_issuer(cert)?;
+    let (_issuer, tbs) = expect_element(tbs, TAG_SEQUENCE)?;
+    let (_validity, tbs) = expect_element(tbs, TAG_SEQUENCE)?;
+    let (mut subject, _) = expect_element(tbs, TAG_SEQUENCE)?;
+
+    // The subject is a sequence of sets of attribute type and value pairs.
+    while !subject.is_empty() {
+        let (mut attributes, rest) = expect_element(subject, TAG_SET)?;
+        subject = rest;
+
+        while !attributes.is_empty() {
+            let (attribute, rest) = expect_element(attributes, TAG_SEQUENCE)?;
+            attributes = rest;
+
+            let (oid, value) = expect_element(attribute, TAG_OID)?;
+            if oid == OID_COMMON_NAME {
+                // All the string types that are allowed here are ASCII compatible.
+                let (_, value, _) = read_element(value)?;
+                return Some(String::from_utf8_lossy(value).into_owned());
+            }
+        }
+    }
+    None
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use rustls::internal::pemfile;
+
+    use std::fs::File;
+    use std::io::BufReader;
+    use std::time::{Duration, UNIX_EPOCH};
+
+    #[test]
+    fn test_subject_common_name() {
+        let file = File::open("tests/tls.pem").unwrap();
+        let certs = pemfile::certs(&mut BufReader::new(file)).unwrap();
+
+        assert_eq!(
+            subject_common_name(&certs[0].0),
+            Some(String::from("localhost"))
+        );
+        assert_eq!(subject_common_name(&certs[0].0[..20]), None);
+        assert_eq!(subject_common_name(&[]), None);
+
+        assert_eq!(
+            serial_number(&certs[0].0).map(|serial| serial[..4].to_vec()),
+            Some(vec![0x5b, 0x95, 0xb8, 0x18])
+        );
+
+        // Mar 16 07:21:00 2124 GMT.
+        assert_eq!(
+            not_after(&certs[0].0),
+            UNIX_EPOCH.checked_add(Duration::from_secs(4_866_247_260))
+        );
+    }
+}
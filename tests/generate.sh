@@ -4,3 +4,9 @@ cfssl gencert -config=int-config.json -ca=ca.pem -ca-key=ca-key.pem intermediate
 cfssl gencert -config=test-config.json -ca intermediate.pem -ca-key intermediate-key.pem test.json | cfssljson -bare tls
 openssl pkcs8 -topk8 -nocrypt -in tls-key.pem -out tls-pkcs8.pem
 cat tls.pem intermediate.pem ca.pem > chain.pem
+openssl rsa -in ca-key.pem -traditional -out ca-key-pkcs1.pem
+openssl pkey -in tls-key.pem -outform DER -out tls-key.der
+openssl req -x509 -new -key ca-key.pem -out rsa.pem -days 36500 -subj "/C=US/ST=CA/L=San Francisco/CN=localhost"
+printf 'V\t21240101000000Z\t\t%s\tunknown\t%s\n' "$(openssl x509 -in tls.pem -noout -serial | cut -d= -f2)" "$(openssl x509 -in tls.pem -noout -subject -nameopt compat | sed 's/subject=//')" > ocsp-index.txt
+openssl ocsp -index ocsp-index.txt -rsigner intermediate.pem -rkey intermediate-key.pem -CA intermediate.pem -issuer intermediate.pem -cert tls.pem -ndays 36500 -respout tls-ocsp.der
+rm ocsp-index.txt